@@ -3,7 +3,7 @@ use alsa::pcm::*;
 use alsa::{Direction, ValueOr};
 
 // use crate::JamEngine;
-use crate::box_error::BoxError;
+use crate::error::FxError;
 
 pub trait SoundCallback {
     fn is_running(&self) -> bool;
@@ -13,7 +13,7 @@ pub trait SoundCallback {
 
 type SF = i16;
 pub const FRAME_SIZE: usize = 128;
-const SAMPLE_RATE: u32 = 48_000;
+pub const SAMPLE_RATE: u32 = 48_000;
 pub const CHANNELS: usize = 2;
 const MAX_SAMPLE: f32 = 32766.0;
 const SMP_FORMAT: Format = Format::s16();
@@ -57,9 +57,10 @@ impl Iterator for OutputBuffer {
     }
 }
 
-fn open_record_dev(device: &str) -> Result<PCM, BoxError> {
-    let pcm = PCM::new(device, Direction::Capture, false)?;
-    {
+fn open_record_dev(device: &str) -> Result<PCM, FxError> {
+    let pcm = PCM::new(device, Direction::Capture, false)
+        .map_err(|e| FxError::DeviceOpen(format!("{}: {}", device, e)))?;
+    let set_params = || -> alsa::Result<()> {
         let hwp = HwParams::any(&pcm)?;
         hwp.set_channels(CHANNELS as u32)?;
         hwp.set_rate(SAMPLE_RATE, ValueOr::Nearest)?;
@@ -67,32 +68,34 @@ fn open_record_dev(device: &str) -> Result<PCM, BoxError> {
         hwp.set_access(Access::RWInterleaved)?;
         hwp.set_buffer_size(2 * FRAME_SIZE as i64)?;
         hwp.set_period_size(FRAME_SIZE as i64, alsa::ValueOr::Nearest)?;
-        pcm.hw_params(&hwp)?;
-    }
+        pcm.hw_params(&hwp)
+    };
+    set_params().map_err(|e| FxError::HwParams(format!("{}: {}", device, e)))?;
     println!("Opened audio input with parameters: {:?}, {:?}", pcm.hw_params_current(), pcm.sw_params_current());
     Ok(pcm)
 }
 
-fn open_playback_dev(device: &str) -> Result<PCM, BoxError> {
+fn open_playback_dev(device: &str) -> Result<PCM, FxError> {
     let req_bufsize: i64 = (FRAME_SIZE * 4) as i64;  // A few ms latency by default, that should be nice
 
     // Open the device
-    let p = alsa::PCM::new(device, alsa::Direction::Playback, false)?;
+    let p = alsa::PCM::new(device, alsa::Direction::Playback, false)
+        .map_err(|e| FxError::DeviceOpen(format!("{}: {}", device, e)))?;
 
-    // Set hardware parameters
-    {
-        let hwp = HwParams::any(&p)?;
-        hwp.set_channels(CHANNELS as u32)?;
-        hwp.set_rate(SAMPLE_RATE, alsa::ValueOr::Nearest)?;
-        hwp.set_format(SMP_FORMAT)?;
-        hwp.set_access(Access::MMapInterleaved)?;
-        hwp.set_buffer_size(req_bufsize)?;
-        hwp.set_period_size(req_bufsize / 4, alsa::ValueOr::Nearest)?;
-        p.hw_params(&hwp)?;
-    }
+    let set_params = || -> alsa::Result<u32> {
+        // Set hardware parameters
+        {
+            let hwp = HwParams::any(&p)?;
+            hwp.set_channels(CHANNELS as u32)?;
+            hwp.set_rate(SAMPLE_RATE, alsa::ValueOr::Nearest)?;
+            hwp.set_format(SMP_FORMAT)?;
+            hwp.set_access(Access::MMapInterleaved)?;
+            hwp.set_buffer_size(req_bufsize)?;
+            hwp.set_period_size(req_bufsize / 4, alsa::ValueOr::Nearest)?;
+            p.hw_params(&hwp)?;
+        }
 
-    // Set software parameters
-    let _rate = {
+        // Set software parameters
         let hwp = p.hw_params_current()?;
         let swp = p.sw_params_current()?;
         let (bufsize, periodsize) = (hwp.get_buffer_size()?, hwp.get_period_size()?);
@@ -100,15 +103,16 @@ fn open_playback_dev(device: &str) -> Result<PCM, BoxError> {
         swp.set_avail_min(periodsize)?;
         p.sw_params(&swp)?;
         println!("Opened audio output {:?} with parameters: {:?}, {:?}", device, hwp, swp);
-        hwp.get_rate()?
+        hwp.get_rate()
     };
+    let _rate = set_params().map_err(|e| FxError::HwParams(format!("{}: {}", device, e)))?;
 
     Ok(p)
 }
 
 
 // fn write_samples_direct(p: &alsa::PCM, mmap: &mut MmapPlayback<SF>, outbuf: &mut OutputBuffer)
-//     -> Result<bool, BoxError> {
+//     -> Result<bool, FxError> {
 
 //     if mmap.avail() > 0 {
 //         // Write samples to DMA area from iterator
@@ -125,12 +129,12 @@ fn open_playback_dev(device: &str) -> Result<PCM, BoxError> {
 //     Ok(true) // Call us again, please, there might be more data to write
 // }
 
-fn write_samples_io(p: &alsa::PCM, io: &mut alsa::pcm::IO<SF>, buf: &mut OutputBuffer) -> Result<bool, BoxError> {
+fn write_samples_io(p: &alsa::PCM, io: &mut alsa::pcm::IO<SF>, buf: &mut OutputBuffer) -> Result<bool, FxError> {
     let avail = match p.avail_update() {
         Ok(n) => n,
         Err(e) => {
             println!("Recovering from {}", e);
-            p.recover(e.errno() as std::os::raw::c_int, true)?;
+            recover(p, e)?;
             p.avail_update()?
         }
     } as usize;
@@ -158,12 +162,18 @@ fn write_samples_io(p: &alsa::PCM, io: &mut alsa::pcm::IO<SF>, buf: &mut OutputB
         State::Running => Ok(false), // All fine
         State::Prepared => { println!("Starting audio output stream"); p.start()?; Ok(true) },
         State::Suspended | State::XRun => Ok(true), // Recover from this in next round
-        n @ _ => Err(format!("Unexpected pcm state {:?}", n))?,
+        n @ _ => Err(FxError::Audio(format!("Unexpected pcm state {:?}", n))),
     }
 }
 
+// Try to get the device going again after an xrun or suspend
+fn recover(p: &alsa::PCM, e: alsa::Error) -> Result<(), FxError> {
+    p.recover(e.errno() as std::os::raw::c_int, true)
+        .map_err(|re| FxError::Xrun(format!("could not recover from {}: {}", e, re)))
+}
+
 // Run the loop to read/write alsa
-pub fn run(engine: &mut dyn SoundCallback, in_device: &str, out_device: &str) -> Result<(), BoxError> {
+pub fn run(engine: &mut dyn SoundCallback, in_device: &str, out_device: &str) -> Result<(), FxError> {
     // stats for callback
     let indev = open_record_dev(in_device)?;
     indev.start()?;
@@ -191,7 +201,7 @@ pub fn run(engine: &mut dyn SoundCallback, in_device: &str, out_device: &str) ->
             }
             Err(e) => {
                 dbg!(e);
-                indev.recover(e.errno() as std::os::raw::c_int, true)?;
+                recover(&indev, e)?;
             }
         }
 
@@ -216,7 +226,7 @@ pub fn run(engine: &mut dyn SoundCallback, in_device: &str, out_device: &str) ->
                 Ok(n) => n,
                 Err(e) => {
                     println!("Recovering from {}", e);
-                    outdev.recover(e.errno() as std::os::raw::c_int, true)?;
+                    recover(&outdev, e)?;
                     outdev.avail_update()?
                 }
             } as usize;
@@ -0,0 +1,251 @@
+//! Compare two board configs so the u/x can mark what changed and offer a revert.
+//!
+//! Pedals are matched by name and occurrence: the second "Delay" on one board is the
+//! second "Delay" on the other.  A matched pedal only counts as moved when its order
+//! relative to the other matched pedals changed, so inserting a pedal at the front
+//! does not make every pedal after it look moved.
+//!
+//! The branches of a matched Parallel Split are compared the same way, branch by branch,
+//! and what changed in them carries a `branch` saying where it is.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Where a board to compare comes from
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "source", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum BoardSource {
+    /// A config array of pedals
+    Config { config: Value },
+    /// A stored preset
+    Preset { id: u64 },
+    /// What is running on a channel right now
+    Live { channel: usize },
+}
+
+/// A branch of a split.  `split` is the index of the split on the second board.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BranchRef {
+    pub split: usize,
+    pub branch: usize,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PedalRef {
+    pub index: usize,
+    pub name: String,
+    /// Null for a pedal on the main chain
+    pub branch: Option<BranchRef>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PedalMove {
+    pub name: String,
+    pub from: usize,
+    pub to: usize,
+    pub branch: Option<BranchRef>,
+}
+
+/// A setting whose value differs.  A value is null when the setting only exists on
+/// one side.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SettingChange {
+    pub pedal: String,
+    /// Index of the pedal on the second board
+    pub index: usize,
+    pub setting: String,
+    pub from: Value,
+    pub to: Value,
+    pub branch: Option<BranchRef>,
+}
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BoardDiff {
+    /// Index on the second board
+    pub added: Vec<PedalRef>,
+    /// Index on the first board
+    pub removed: Vec<PedalRef>,
+    pub moved: Vec<PedalMove>,
+    pub changed: Vec<SettingChange>,
+}
+
+fn pedals(config: &Value) -> Vec<&Value> {
+    config.as_array().map(|list| list.iter().collect()).unwrap_or_default()
+}
+
+fn pedal_name(pedal: &Value) -> String {
+    pedal["name"].as_str().unwrap_or_default().to_string()
+}
+
+/// Indexes into `to` for each matched pedal of `from` (by name and occurrence)
+fn match_pedals(from: &[&Value], to: &[&Value]) -> Vec<Option<usize>> {
+    let mut used = vec![false; to.len()];
+    from.iter()
+        .map(|pedal| {
+            let name = pedal_name(pedal);
+            let found = (0..to.len()).find(|&j| !used[j] && pedal_name(to[j]) == name);
+            if let Some(j) = found {
+                used[j] = true;
+            }
+            found
+        })
+        .collect()
+}
+
+/// Positions (into `seq`) of a longest increasing subsequence
+fn longest_increasing(seq: &[usize]) -> Vec<usize> {
+    let mut length = vec![1; seq.len()];
+    let mut prev: Vec<Option<usize>> = vec![None; seq.len()];
+    for i in 0..seq.len() {
+        for j in 0..i {
+            if seq[j] < seq[i] && length[j] + 1 > length[i] {
+                length[i] = length[j] + 1;
+                prev[i] = Some(j);
+            }
+        }
+    }
+    let mut result = Vec::new();
+    let mut pos = (0..seq.len()).max_by_key(|&i| length[i]);
+    while let Some(i) = pos {
+        result.push(i);
+        pos = prev[i];
+    }
+    result.reverse();
+    result
+}
+
+fn diff_settings(pedal: String, index: usize, branch: Option<BranchRef>, from: &Value, to: &Value, changed: &mut Vec<SettingChange>) {
+    let settings = |p: &Value| p["settings"].as_array().cloned().unwrap_or_default();
+    let (from_settings, to_settings) = (settings(from), settings(to));
+    let find = |list: &[Value], name: &Value| list.iter().find(|s| s["name"] == *name).map(|s| s["value"].clone());
+    let mut change = |setting: &Value, a: Value, b: Value| {
+        changed.push(SettingChange {
+            pedal: pedal.clone(),
+            index,
+            setting: setting.as_str().unwrap_or_default().to_string(),
+            from: a,
+            to: b,
+            branch,
+        })
+    };
+    for setting in from_settings.iter() {
+        let old = setting["value"].clone();
+        match find(&to_settings, &setting["name"]) {
+            Some(new) if new == old => {}
+            Some(new) => change(&setting["name"], old, new),
+            None => change(&setting["name"], old, Value::Null),
+        }
+    }
+    for setting in to_settings.iter() {
+        if find(&from_settings, &setting["name"]).is_none() {
+            change(&setting["name"], Value::Null, setting["value"].clone());
+        }
+    }
+}
+
+/// What it takes to get from one board config to another
+pub fn diff_boards(from: &Value, to: &Value) -> BoardDiff {
+    let mut diff = BoardDiff::default();
+    diff_pedals(from, to, None, &mut diff);
+    diff
+}
+
+// Add the differences between two lists of pedals (a board or a branch) to the diff
+fn diff_pedals(from: &Value, to: &Value, branch: Option<BranchRef>, diff: &mut BoardDiff) {
+    let (from, to) = (pedals(from), pedals(to));
+    let matches = match_pedals(&from, &to);
+
+    let pairs: Vec<(usize, usize)> = matches
+        .iter()
+        .enumerate()
+        .filter_map(|(i, m)| m.map(|j| (i, j)))
+        .collect();
+    let in_order = longest_increasing(&pairs.iter().map(|&(_, j)| j).collect::<Vec<usize>>());
+    for (n, &(i, j)) in pairs.iter().enumerate() {
+        if !in_order.contains(&n) {
+            diff.moved.push(PedalMove { name: pedal_name(from[i]), from: i, to: j, branch });
+        }
+        diff_settings(pedal_name(to[j]), j, branch, from[i], to[j], &mut diff.changed);
+        let branches = |pedal: &Value| pedal["branches"].as_array().map_or(0, |branches| branches.len());
+        for b in 0..branches(from[i]).max(branches(to[j])) {
+            let inner = Some(BranchRef { split: j, branch: b });
+            diff_pedals(&from[i]["branches"][b], &to[j]["branches"][b], inner, diff);
+        }
+    }
+    for (i, m) in matches.iter().enumerate() {
+        if m.is_none() {
+            diff.removed.push(PedalRef { index: i, name: pedal_name(from[i]), branch });
+        }
+    }
+    for (j, pedal) in to.iter().enumerate() {
+        if !pairs.iter().any(|&(_, pj)| pj == j) {
+            diff.added.push(PedalRef { index: j, name: pedal_name(pedal), branch });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_board_diff {
+    use super::*;
+    use serde_json::json;
+
+    fn pedal(name: &str, level: f64) -> Value {
+        json!({ "name": name, "settings": [{ "name": "bypass", "value": false }, { "name": "level", "value": level }] })
+    }
+
+    #[test]
+    fn same_board() {
+        let board = json!([pedal("Delay", 0.5), pedal("Chorus", 0.2)]);
+        assert_eq!(diff_boards(&board, &board), BoardDiff::default());
+    }
+    #[test]
+    fn insert_is_not_a_move() {
+        let a = json!([pedal("Delay", 0.5), pedal("Chorus", 0.2)]);
+        let b = json!([pedal("Tremelo", 0.5), pedal("Delay", 0.5), pedal("Chorus", 0.2)]);
+        let diff = diff_boards(&a, &b);
+        assert_eq!(diff.added, vec![PedalRef { index: 0, name: String::from("Tremelo"), branch: None }]);
+        assert!(diff.moved.is_empty());
+        assert!(diff.changed.is_empty());
+        let diff = diff_boards(&b, &a);
+        assert_eq!(diff.removed, vec![PedalRef { index: 0, name: String::from("Tremelo"), branch: None }]);
+    }
+    #[test]
+    fn moves_and_changes() {
+        let a = json!([pedal("Delay", 0.5), pedal("Chorus", 0.2), pedal("Delay", 0.1)]);
+        let b = json!([pedal("Chorus", 0.3), pedal("Delay", 0.5), pedal("Delay", 0.1)]);
+        let diff = diff_boards(&a, &b);
+        assert_eq!(diff.moved.len(), 1);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].pedal, "Chorus");
+        assert_eq!(diff.changed[0].index, 0);
+        assert_eq!((diff.changed[0].from.clone(), diff.changed[0].to.clone()), (json!(0.2), json!(0.3)));
+    }
+    #[test]
+    fn missing_settings() {
+        let a = json!([{ "name": "Delay", "settings": [{ "name": "duration", "value": 200 }] }]);
+        let b = json!([{ "name": "Delay", "settings": [{ "name": "time", "value": 200 }] }]);
+        let diff = diff_boards(&a, &b);
+        assert_eq!(diff.changed.len(), 2);
+        assert_eq!(diff.changed[0].to, Value::Null);
+        assert_eq!(diff.changed[1].from, Value::Null);
+    }
+    #[test]
+    fn split_branches() {
+        let split = |a: Value, b: Value| json!({ "name": "Parallel Split", "settings": [], "branches": [a, b] });
+        let a = json!([pedal("Delay", 0.5), split(json!([pedal("Chorus", 0.2)]), json!([]))]);
+        let b = json!([split(json!([pedal("Chorus", 0.4)]), json!([pedal("Delay", 0.1)])), pedal("Delay", 0.5)]);
+        let diff = diff_boards(&a, &b);
+        let inside = |branch| Some(BranchRef { split: 0, branch });
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].pedal, "Chorus");
+        assert_eq!(diff.changed[0].branch, inside(0));
+        assert_eq!(diff.added, vec![PedalRef { index: 0, name: String::from("Delay"), branch: inside(1) }]);
+        assert_eq!(diff.moved.len(), 1);
+        assert_eq!(diff.moved[0].branch, None);
+    }
+}
@@ -1,47 +1,85 @@
-use std::{str::FromStr, sync::mpsc::{self, Receiver, Sender}, thread::JoinHandle};
+use std::{path::Path, sync::mpsc::{self, Receiver, Sender}, thread::{self, JoinHandle}, time::{Duration, Instant}};
 
 use log::{debug, error, info};
-use pedal_board::PedalBoard;
 use pedal_board::dsp::{power_meter::PowerMeter, tuner::Tuner};
 use tauri::ipc::Channel;
 use thread_priority::{ThreadBuilder, ThreadPriority};
-use crate::{alsa_thread::{self, SoundCallback, CHANNELS, FRAME_SIZE}, box_error::BoxError, param_message::{JamParam, ParamMessage}, utils::{get_micro_time, MicroTimer}};
+use crate::{alsa_thread::{self, SoundCallback, CHANNELS, FRAME_SIZE, SAMPLE_RATE}, error::FxError, history::{BoardEdit, EditHistory, LockChange}, looper::{LoopMemory, Looper, LooperAction, LooperState}, loudness::LoudnessMeter, metronome::Metronome, mixer::{MixerState, BUSES}, param_message::{CommandRequest, JamCommand}, preset_schema, recorder::{RecordTap, Recorder}, scope::{Scope, ScopeTap}, session::{SessionSnapshot, SessionStore, SESSION_DEBOUNCE}, stereo_board::{StereoBoard, BRANCHES, SPLIT, WIDENER}, tempo::{self, NoteDivision, SharedTempo, Tempo, TempoLock, TempoSource, TimeSignature, DEFAULT_BPM}, track_player::{TrackPlayer, TrackTap}, utils::{get_micro_time, MicroTimer}};
 use serde_json::{json, Value};
 
 /// The BoardConnection will retain the channel to the alsa thread
 pub struct BoardConnection {
-    cmd_tx: Option<Sender<ParamMessage>>,
+    cmd_tx: Option<Sender<CommandRequest>>,
+    event_channel: Option<Channel<Value>>,
     handle: Option<JoinHandle<()>>,
+    tempo: SharedTempo,
+    recorder: Option<Recorder>,
+    player: Option<TrackPlayer>,
+    /// Hands the loop buffers to the audio thread, taken when the looper first records
+    loop_memory_tx: Option<Sender<LoopMemory>>,
 }
 
 impl BoardConnection {
     pub fn new() -> BoardConnection {
         BoardConnection {
             cmd_tx: None,
+            event_channel: None,
             handle: None,
+            tempo: SharedTempo::new(DEFAULT_BPM),
+            recorder: None,
+            player: None,
+            loop_memory_tx: None,
         }
     }
     // Gentlemen, start your engines..
-    pub fn start(&mut self, channel: Channel<Value>, in_dev: String, out_dev: String) -> Result<(), BoxError> {
+    pub fn start(&mut self, channel: Channel<Value>, in_dev: String, out_dev: String, session: SessionStore) -> Result<(), FxError> {
         info!("starting audio: {}, {}", in_dev, out_dev);
         // Prevent double start
         if  self.cmd_tx.is_some() {
             // we have already been started
             error!("attempting to double start audio");
-            return Err("Cannot start over!".into());
+            return Err(FxError::EngineAlreadyRunning);
         }
 
         // Create a channel to talk to the audio thread
-        let (command_tx, command_rx): (mpsc::Sender<ParamMessage>, mpsc::Receiver<ParamMessage>) = mpsc::channel();
+        let (command_tx, command_rx): (mpsc::Sender<CommandRequest>, mpsc::Receiver<CommandRequest>) = mpsc::channel();
 
         self.cmd_tx = Some(command_tx);
+        self.event_channel = Some(channel.clone());
 
+        // Session snapshots get written to disk off the audio thread.  The writer
+        // ends when the audio thread drops its sender.
+        let (session_tx, session_rx): (Sender<SessionSnapshot>, Receiver<SessionSnapshot>) = mpsc::channel();
+        thread::Builder::new().name("Session Writer".to_string()).spawn(move || {
+            for snapshot in session_rx {
+                if let Err(e) = session.update(|s| {
+                    s.boards = snapshot.boards;
+                    s.mixer = snapshot.mixer;
+                    s.buses = snapshot.buses;
+                    s.bpm = snapshot.bpm;
+                    s.time_signature = snapshot.time_signature;
+                    s.tempo_locks = snapshot.tempo_locks;
+                }) {
+                    error!("failed to save session: {}", e);
+                }
+            }
+        })?;
+
+        let (recorder, record_tap) = Recorder::new(channel.clone());
+        self.recorder = Some(recorder);
+        let (player, track_tap) = TrackPlayer::new(channel.clone());
+        self.player = Some(player);
+
+        let (loop_memory_tx, loop_memory_rx) = mpsc::channel();
+        self.loop_memory_tx = Some(loop_memory_tx);
+
+        let tempo = self.tempo.clone();
         let builder = ThreadBuilder::default()
             .name("Real-Time Thread".to_string())
             .priority(ThreadPriority::Max);
 
         let alsa_handle = builder.spawn(move |_result| {
-            match alsa_thread::run(&mut BoardSet::new(channel, command_rx), &in_dev, &out_dev) {
+            match alsa_thread::run(&mut BoardSet::new(channel, command_rx, session_tx, loop_memory_rx, tempo, record_tap, track_tap), &in_dev, &out_dev) {
                 Ok(()) => {
                     info!("alsa ended with OK");
                 }
@@ -54,55 +92,162 @@ impl BoardConnection {
         Ok(())
     }
 
-    pub fn stop(&mut self) -> Result<(), BoxError> {
+    pub fn stop(&mut self) -> Result<(), FxError> {
         // Prevent double stop
         if self.cmd_tx.is_none() {
             // we are already stopped
             error!("attempting double stop of audio");
-            return Err("Double Stop".into());
+            return Err(FxError::EngineNotRunning);
         }
-        match self.send_command(
-            ParamMessage::new(JamParam::ShutdownAudio, 0, 0, 0.0, "")
-        ) {
+        // Close off a take and the track player before the audio goes away
+        if let Some(mut recorder) = self.recorder.take() {
+            if recorder.is_recording() {
+                if let Err(e) = recorder.stop() {
+                    error!("could not stop recording: {}", e);
+                }
+            }
+        }
+        if let Some(mut player) = self.player.take() {
+            if player.is_loaded() {
+                if let Err(e) = player.unload() {
+                    error!("could not unload track: {}", e);
+                }
+            }
+        }
+        match self.send_command(CommandRequest::new(JamCommand::ShutdownAudio)) {
             Ok(()) => { () }
             Err(e) => {
                 error!("could not send command to stop: {}", e);
             }
         }
         self.cmd_tx = None;
+        self.event_channel = None;
+        self.loop_memory_tx = None;
         Ok(())
     }
 
     // This will send a command to the box thread
-    pub fn send_command(&mut self, msg: ParamMessage) -> Result<(), BoxError> {
-        if let Some(tx) = &self.cmd_tx {
-            tx.send(msg)?;
+    pub fn send_command(&mut self, msg: CommandRequest) -> Result<(), FxError> {
+        match &self.cmd_tx {
+            Some(tx) => {
+                // The loop buffers are allocated here rather than on the audio thread,
+                // and only once somebody actually records a loop
+                if matches!(msg.command, JamCommand::Looper { action: LooperAction::Record, .. }) {
+                    if let Some(memory_tx) = self.loop_memory_tx.take() {
+                        memory_tx.send(LoopMemory::new())?;
+                    }
+                }
+                tx.send(msg)?;
+                Ok(())
+            }
+            None => Err(FxError::EngineNotRunning),
+        }
+    }
+
+    // Send a command and wait for the data it produces
+    pub fn request(&mut self, command: JamCommand) -> Result<Value, FxError> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        self.send_command(CommandRequest {
+            request_id: None,
+            command,
+            reply: Some(reply_tx),
+        })?;
+        reply_rx
+            .recv_timeout(Duration::from_secs(1))
+            .map_err(|e| FxError::Timeout(format!("no reply from audio thread: {}", e)))
+    }
+
+    /// Record dry and wet stems into `dir` until [`stop_recording`](Self::stop_recording)
+    pub fn start_recording(&mut self, dir: &Path, name: &str) -> Result<Value, FxError> {
+        self.recorder.as_mut().ok_or(FxError::EngineNotRunning)?.start(dir, name)
+    }
+
+    pub fn stop_recording(&mut self) -> Result<Value, FxError> {
+        self.recorder.as_mut().ok_or(FxError::EngineNotRunning)?.stop()
+    }
+
+    /// The backing track player
+    pub fn player(&mut self) -> Result<&mut TrackPlayer, FxError> {
+        self.player.as_mut().ok_or(FxError::EngineNotRunning)
+    }
+
+    /// The current BPM, kept up to date by the audio thread
+    pub fn tempo(&self) -> SharedTempo {
+        self.tempo.clone()
+    }
+
+    // Send an event to the u/x from outside the audio thread
+    pub fn send_event(&self, event: Value) -> Result<(), FxError> {
+        match &self.event_channel {
+            Some(channel) => {
+                channel.send(event)?;
+                Ok(())
+            }
+            None => Err(FxError::EngineNotRunning),
         }
-        Ok(())
     }
 }
 
 pub struct BoardSet {
-    boards: [PedalBoard; CHANNELS],
+    boards: [StereoBoard; CHANNELS],
+    history: [EditHistory; CHANNELS],
     pub event_channel: Channel<Value>,
-    pub rx_cmd: Receiver<ParamMessage>,
+    pub rx_cmd: Receiver<CommandRequest>,
     pub running: bool,
     input_meters: [PowerMeter; CHANNELS],
     output_meters: [PowerMeter; CHANNELS],
-    output_buffers: [Vec<f32>; 2],
+    loudness: LoudnessMeter,
+    /// Left and right out of each board
+    output_buffers: [[Vec<f32>; 2]; CHANNELS],
+    /// Shared effects chains fed by the board sends
+    buses: [StereoBoard; BUSES],
+    send_buffer: Vec<f32>,
+    /// Both sides of a stereo board mixed down for the meters and scope
+    mid_buffer: Vec<f32>,
+    bus_buffers: [[Vec<f32>; 2]; BUSES],
     tuners: [Tuner; 2],
+    mixer: MixerState,
+    scope: Scope,
+    tempo: Tempo,
+    tempo_locks: [TempoLock; CHANNELS],
+    looper: Looper,
+    loop_memory_rx: Receiver<LoopMemory>,
+    metronome: Metronome,
+    recorder: RecordTap,
+    track: TrackTap,
+    session_tx: Sender<SessionSnapshot>,
+    session_dirty: bool,
+    session_timer: MicroTimer,
     update_timer: MicroTimer,
     frame_count: usize,
 }
 
 impl BoardSet {
-    pub fn new(channel: Channel<Value>, rx_cmd: Receiver<ParamMessage>) -> BoardSet {
+    pub fn new(channel: Channel<Value>, rx_cmd: Receiver<CommandRequest>, session_tx: Sender<SessionSnapshot>, loop_memory_rx: Receiver<LoopMemory>, tempo: SharedTempo, recorder: RecordTap, track: TrackTap) -> BoardSet {
         BoardSet {
-            boards: [PedalBoard::new(0), PedalBoard::new(1)],
+            boards: [StereoBoard::new(0), StereoBoard::new(1)],
+            history: [EditHistory::new(), EditHistory::new()],
             input_meters: [PowerMeter::new(), PowerMeter::new()],
             output_meters: [PowerMeter::new(), PowerMeter::new()],
-            output_buffers: [vec!(0.0; FRAME_SIZE), vec!(0.0; FRAME_SIZE)],
+            loudness: LoudnessMeter::new(),
+            output_buffers: std::array::from_fn(|_| [vec!(0.0; FRAME_SIZE), vec!(0.0; FRAME_SIZE)]),
+            buses: std::array::from_fn(StereoBoard::new),
+            send_buffer: vec!(0.0; FRAME_SIZE),
+            mid_buffer: vec!(0.0; FRAME_SIZE),
+            bus_buffers: std::array::from_fn(|_| [vec!(0.0; FRAME_SIZE), vec!(0.0; FRAME_SIZE)]),
             tuners: [Tuner::new(), Tuner::new()],
+            mixer: MixerState::default(),
+            scope: Scope::new(),
+            tempo: Tempo::new(tempo),
+            tempo_locks: Default::default(),
+            looper: Looper::new(),
+            loop_memory_rx,
+            metronome: Metronome::new(),
+            recorder,
+            track,
+            session_tx,
+            session_dirty: false,
+            session_timer: MicroTimer::new(get_micro_time(), SESSION_DEBOUNCE),
             event_channel: channel,
             rx_cmd: rx_cmd,
             running: true,
@@ -111,65 +256,406 @@ impl BoardSet {
         }
     }
     fn process_command(&mut self) -> () {
-        if let Ok(msg) = self.rx_cmd.try_recv() {
-            info!("got message: {}", msg);
-            match msg.param {
-                JamParam::ShutdownAudio => {
-                    self.running = false;
-                }
-                JamParam::GetConfigJson => {
-                    match self.event_channel.send(self.board_config()) {
-                        Ok(()) => {}
-                        Err(e) => {
-                            error!("Error retrieving board config: {}", e);
-                        }
+        if let Ok(req) = self.rx_cmd.try_recv() {
+            info!("got command: {:?}", req);
+            let is_edit = req.command.is_edit();
+            let result = match self.execute(req.command) {
+                Ok(Some(data)) => {
+                    // Data goes back to whoever asked, or to the u/x
+                    match &req.reply {
+                        Some(reply) => reply.send(data).map_err(FxError::from),
+                        None => self.event_channel.send(data).map_err(FxError::from),
                     }
                 }
-                JamParam::LoadBoard => {
-                    let idx = msg.ivalue_1 as usize;
-                    if idx < CHANNELS {
-                        self.boards[idx] = PedalBoard::new(idx);
-                        self.boards[idx].load_from_json(&msg.svalue);
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+            match &result {
+                Ok(()) if is_edit => {
+                    // Save the session once the edits settle down
+                    self.session_dirty = true;
+                    self.session_timer.reset(get_micro_time());
+                }
+                Ok(()) => {}
+                Err(e) => error!("command failed: {}", e),
+            }
+            // Let the u/x know how the command went if it asked
+            if let Some(request_id) = req.request_id {
+                if let Err(e) = self.event_channel.send(self.ack(request_id, result)) {
+                    error!("failed to send ack: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Run a command, returning any data it produces
+    fn execute(&mut self, cmd: JamCommand) -> Result<Option<Value>, FxError> {
+        match cmd {
+            JamCommand::ShutdownAudio => {
+                if self.session_dirty {
+                    self.save_session();
+                }
+                self.running = false;
+            }
+            JamCommand::GetConfigJson => {
+                return Ok(Some(self.board_config()));
+            }
+            JamCommand::LoadBoard { channel, board } => {
+                check_channel(channel)?;
+                if !board.is_array() {
+                    return Err(FxError::ProtocolParse(String::from("board config must be an array of pedals")));
+                }
+                self.boards[channel] = StereoBoard::new(channel);
+                self.boards[channel].load_from_json(&board.to_string());
+                self.history[channel].clear();
+                self.sync_to_tempo(channel)?;
+            }
+            JamCommand::LoadBoards { boards } => {
+                for board in boards.iter().filter(|board| !board.is_null()) {
+                    if !board.is_array() {
+                        return Err(FxError::ProtocolParse(String::from("board config must be an array of pedals")));
                     }
                 }
-                JamParam::InsertPedal => {
-                    let idx = msg.ivalue_1 as usize;
-                    if idx < CHANNELS {
-                        self.boards[idx].insert_pedal(&msg.svalue, msg.ivalue_2 as usize);
+                // Build and sync every board before swapping any of them in
+                let mut loaded = Vec::with_capacity(CHANNELS);
+                for (channel, board) in boards.iter().enumerate().filter(|(_, board)| !board.is_null()) {
+                    let mut new_board = StereoBoard::new(channel);
+                    new_board.load_from_json(&board.to_string());
+                    sync_board(&mut new_board, channel, &self.tempo_locks[channel], self.tempo.bpm)?;
+                    loaded.push((channel, new_board));
+                }
+                for (channel, board) in loaded {
+                    self.boards[channel] = board;
+                    self.history[channel].clear();
+                }
+            }
+            JamCommand::InsertPedal { channel, index, pedal_type } => {
+                check_channel(channel)?;
+                if StereoBoard::get_pedal_types().get(&pedal_type).is_none() {
+                    return Err(FxError::UnknownPedal(pedal_type));
+                }
+                if pedal_type == WIDENER && self.boards[channel].is_stereo() {
+                    return Err(FxError::ProtocolParse(String::from("board is already stereo")));
+                }
+                check_index(index, self.pedal_count(channel) + 1)?;
+                self.boards[channel].insert_pedal(&pedal_type, index);
+                self.sync_to_tempo(channel)?;
+                let pedal = self.pedal_json(channel, index);
+                self.history[channel].record(BoardEdit::Insert { index, pedal }, Instant::now());
+            }
+            JamCommand::DeletePedal { channel, index } => {
+                check_channel(channel)?;
+                check_index(index, self.pedal_count(channel))?;
+                let pedal = self.pedal_json(channel, index);
+                self.boards[channel].delete_pedal(index);
+                self.history[channel].record(BoardEdit::Delete { index, pedal }, Instant::now());
+            }
+            JamCommand::MovePedal { channel, from, to } => {
+                check_channel(channel)?;
+                check_index(from, self.pedal_count(channel))?;
+                check_index(to, self.pedal_count(channel))?;
+                self.boards[channel].move_pedal(from, to);
+                self.history[channel].record(BoardEdit::Move { from, to }, Instant::now());
+            }
+            JamCommand::InsertBranchPedal { channel, index, branch, position, pedal_type } => {
+                // Branches only hold ordinary pedals
+                if pedal_type == WIDENER || pedal_type == SPLIT || StereoBoard::get_pedal_types().get(&pedal_type).is_none() {
+                    return Err(FxError::UnknownPedal(pedal_type));
+                }
+                check_index(position, self.branch_len(channel, index, branch)? + 1)?;
+                let before = self.pedal_json(channel, index);
+                self.boards[channel].insert_branch_pedal(index, branch, position, &pedal_type);
+                self.record_replace(channel, index, before);
+            }
+            JamCommand::DeleteBranchPedal { channel, index, branch, position } => {
+                check_index(position, self.branch_len(channel, index, branch)?)?;
+                let before = self.pedal_json(channel, index);
+                self.boards[channel].delete_branch_pedal(index, branch, position);
+                self.record_replace(channel, index, before);
+            }
+            JamCommand::MoveBranchPedal { channel, index, branch, from, to } => {
+                let len = self.branch_len(channel, index, branch)?;
+                check_index(from, len)?;
+                check_index(to, len)?;
+                let before = self.pedal_json(channel, index);
+                self.boards[channel].move_branch_pedal(index, branch, from, to);
+                self.record_replace(channel, index, before);
+            }
+            JamCommand::SetBranchConfig { channel, index, branch, position, setting } => {
+                check_index(position, self.branch_len(channel, index, branch)?)?;
+                let name = setting["name"]
+                    .as_str()
+                    .ok_or_else(|| FxError::ProtocolParse(String::from("setting has no name")))?;
+                let before = self.pedal_json(channel, index);
+                let pedal = &before["branches"][branch][position];
+                if !pedal["settings"].as_array().is_some_and(|settings| settings.iter().any(|s| s["name"] == name)) {
+                    return Err(FxError::UnknownSetting(format!("branch pedal {} has no setting {}", position, name)));
+                }
+                if setting["value"].is_string() {
+                    return Err(FxError::ProtocolParse(String::from("note divisions only work on the main chain")));
+                }
+                self.boards[channel].change_branch_value(index, branch, position, &setting);
+                self.record_replace(channel, index, before);
+            }
+            JamCommand::SetEffectConfig { channel, pedal, setting } => {
+                check_channel(channel)?;
+                check_index(pedal, self.pedal_count(channel))?;
+                let name = setting["name"]
+                    .as_str()
+                    .ok_or_else(|| FxError::ProtocolParse(String::from("setting has no name")))?;
+                let pedal_json = self.pedal_json(channel, pedal);
+                let current = pedal_json["settings"]
+                    .as_array()
+                    .and_then(|settings| settings.iter().find(|s| s["name"] == name))
+                    .ok_or_else(|| FxError::UnknownSetting(format!("pedal {} has no setting {}", pedal, name)))?;
+                let previous = json!({ "name": name, "value": current["value"] });
+                // A note division ("1/8d") on a time based setting follows the tempo
+                let mut lock_change = None;
+                let setting = match setting["value"].as_str() {
+                    Some(division_str) => {
+                        let division: NoteDivision = division_str.parse()?;
+                        let pedal_name = pedal_json["name"].as_str().unwrap_or_default();
+                        let value = tempo::synced_value(pedal_name, current, &division, self.tempo.bpm)?;
+                        // A locked board keeps this pedal type at this division
+                        let lock = &mut self.tempo_locks[channel];
+                        if lock.enabled {
+                            let before = lock.clone();
+                            lock.divisions.insert(pedal_name.to_string(), String::from(division_str));
+                            if *lock != before {
+                                lock_change = Some(LockChange { before, after: lock.clone() });
+                            }
+                        }
+                        json!({ "name": name, "value": value })
                     }
+                    None => setting,
+                };
+                self.boards[channel].change_value(pedal, &setting);
+                self.history[channel].record(BoardEdit::SetValue { pedal, setting, previous, lock: lock_change }, Instant::now());
+            }
+            JamCommand::SetTempo { bpm, source: TempoSource::MidiClock } => {
+                // Don't rewrite the locked pedals over clock jitter
+                if self.tempo.follow_clock(bpm) {
+                    self.sync_all_to_tempo()?;
+                    return Ok(Some(self.tempo.as_json()));
+                }
+            }
+            JamCommand::SetTempo { bpm, source } => {
+                self.tempo.set(bpm, source);
+                self.sync_all_to_tempo()?;
+                return Ok(Some(self.tempo.as_json()));
+            }
+            JamCommand::TapTempo => {
+                if self.tempo.tap(Instant::now()) {
+                    self.sync_all_to_tempo()?;
+                    return Ok(Some(self.tempo.as_json()));
                 }
-                JamParam::DeletePedal => {
-                    let idx = msg.ivalue_1 as usize;
-                    if idx < CHANNELS {
-                        self.boards[idx].delete_pedal(msg.ivalue_2 as usize);
+            }
+            JamCommand::SetTimeSignature { beats, unit } => {
+                if beats == 0 || !unit.is_power_of_two() {
+                    return Err(FxError::ProtocolParse(format!("bad time signature {}/{}", beats, unit)));
+                }
+                self.tempo.time_signature = TimeSignature { beats, unit };
+                return Ok(Some(self.tempo.as_json()));
+            }
+            JamCommand::SetTempoLock { channel, lock } => {
+                check_channel(channel)?;
+                self.tempo_locks[channel] = lock;
+                self.sync_to_tempo(channel)?;
+            }
+            JamCommand::Looper { action, channel } => {
+                if action == LooperAction::Record && self.looper.state == LooperState::Empty {
+                    if let Some(channel) = channel {
+                        check_channel(channel)?;
                     }
+                    self.looper.channel = channel;
+                    // Sent ahead of the command the first time a loop is recorded
+                    if self.looper.needs_memory() {
+                        if let Ok(memory) = self.loop_memory_rx.try_recv() {
+                            self.looper.give_memory(memory);
+                        }
+                    }
+                }
+                self.looper.action(action, self.tempo.bar_samples(SAMPLE_RATE as f64));
+                return Ok(Some(json!({ "looperEvent": self.looper.as_json() })));
+            }
+            JamCommand::LooperQuantize { enabled } => {
+                self.looper.quantize = enabled;
+                return Ok(Some(json!({ "looperEvent": self.looper.as_json() })));
+            }
+            JamCommand::SetMetronome { settings } => {
+                self.metronome.set(settings);
+                return Ok(Some(json!({ "metronomeEvent": settings })));
+            }
+            JamCommand::Undo { channel } => {
+                check_channel(channel)?;
+                let edit = self.history[channel]
+                    .undo()
+                    .ok_or_else(|| FxError::NotFound(format!("nothing to undo on channel {}", channel)))?;
+                edit.undo(&mut self.boards[channel], &mut self.tempo_locks[channel]);
+            }
+            JamCommand::Redo { channel } => {
+                check_channel(channel)?;
+                let edit = self.history[channel]
+                    .redo()
+                    .ok_or_else(|| FxError::NotFound(format!("nothing to redo on channel {}", channel)))?;
+                edit.redo(&mut self.boards[channel], &mut self.tempo_locks[channel]);
+            }
+            JamCommand::GetScope { channel, tap, milliseconds, points } => {
+                return Ok(Some(self.scope.snapshot(tap, channel, milliseconds, points)?));
+            }
+            JamCommand::ResetLoudness => {
+                self.loudness.reset();
+            }
+            JamCommand::SetChannelMix { channel, level, mute, pan } => {
+                check_channel(channel)?;
+                self.mixer.channels[channel].level = level;
+                self.mixer.channels[channel].mute = mute;
+                if let Some(pan) = pan {
+                    self.mixer.channels[channel].pan = pan.clamp(-1.0, 1.0);
                 }
-                JamParam::MovePedal => {
-                    let idx = msg.ivalue_1 as usize;
-                    if idx < CHANNELS {
-                        let from_idx: usize = msg.ivalue_2 as usize;
-                        let to_idx: usize = msg.fvalue.round() as usize;
-                        self.boards[idx].move_pedal(from_idx, to_idx);
+            }
+            JamCommand::SetOutputRouting { routing } => {
+                self.mixer.routing = routing;
+            }
+            JamCommand::SetSend { channel, bus, level, on } => {
+                check_channel(channel)?;
+                check_bus(bus)?;
+                self.mixer.sends[channel][bus].level = level;
+                self.mixer.sends[channel][bus].on = on;
+            }
+            JamCommand::SetBusReturn { bus, level, mute, pan } => {
+                check_bus(bus)?;
+                self.mixer.returns[bus].level = level;
+                self.mixer.returns[bus].mute = mute;
+                if let Some(pan) = pan {
+                    self.mixer.returns[bus].pan = pan.clamp(-1.0, 1.0);
+                }
+            }
+            JamCommand::LoadBus { bus, board } => {
+                check_bus(bus)?;
+                preset_schema::validate_config(&board)?;
+                self.buses[bus] = StereoBoard::new(bus);
+                self.buses[bus].load_from_json(&board.to_string());
+            }
+            JamCommand::CaptureScene => {
+                return Ok(Some(json!({
+                    "sceneEvent": {
+                        "boards": [
+                            self.boards[0].as_json(0)["effects"],
+                            self.boards[1].as_json(1)["effects"],
+                        ],
+                        "mixer": self.mixer,
+                        "buses": [
+                            self.buses[0].as_json(0)["effects"],
+                            self.buses[1].as_json(1)["effects"],
+                        ],
                     }
+                })));
+            }
+            JamCommand::RecallScene { boards, mixer, buses } => {
+                for board in boards.iter().chain(buses.iter().filter(|bus| !bus.is_null())) {
+                    preset_schema::validate_config(board)?;
                 }
-                JamParam::SetEffectConfig => {
-                    let idx = msg.ivalue_1 as usize;
-                    if idx < CHANNELS {
-                        match serde_json::Value::from_str(&msg.svalue) {
-                            Ok(setting) => {
-                                self.boards[idx].change_value(msg.ivalue_2 as usize, &setting);
-                            }
-                            Err(e) => {
-                                // error parsing json to modify a setting
-                                dbg!(e);
-                            }
-                        }
-
-                        self.boards[idx].load_from_json(&msg.svalue);
+                // Build both boards before swapping them in
+                let mut new_boards = [StereoBoard::new(0), StereoBoard::new(1)];
+                for (idx, board) in boards.iter().enumerate() {
+                    new_boards[idx].load_from_json(&board.to_string());
+                }
+                self.boards = new_boards;
+                for (idx, bus) in buses.iter().enumerate() {
+                    if !bus.is_null() {
+                        self.buses[idx] = StereoBoard::new(idx);
+                        self.buses[idx].load_from_json(&bus.to_string());
                     }
                 }
+                self.mixer = mixer;
+                for history in self.history.iter_mut() {
+                    history.clear();
+                }
+                self.sync_all_to_tempo()?;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Number of pedals currently on a board
+    fn pedal_count(&self, channel: usize) -> usize {
+        self.boards[channel].as_json(channel)["effects"]
+            .as_array()
+            .map_or(0, |effects| effects.len())
+    }
+
+    /// Number of pedals on a branch of the split at `index`
+    fn branch_len(&self, channel: usize, index: usize, branch: usize) -> Result<usize, FxError> {
+        check_channel(channel)?;
+        check_index(index, self.pedal_count(channel))?;
+        if branch >= BRANCHES {
+            return Err(FxError::IndexOutOfRange(format!("branch {}", branch)));
+        }
+        self.boards[channel]
+            .branch_len(index, branch)
+            .ok_or_else(|| FxError::ProtocolParse(format!("pedal {} is not a split", index)))
+    }
+
+    /// Record a branch edit on the split at `index` as a swap of its json
+    fn record_replace(&mut self, channel: usize, index: usize, before: Value) {
+        let after = self.pedal_json(channel, index);
+        self.history[channel].record(BoardEdit::Replace { index, before, after }, Instant::now());
+    }
+
+    /// Sync a channel's running board to the tempo
+    fn sync_to_tempo(&mut self, channel: usize) -> Result<(), FxError> {
+        sync_board(&mut self.boards[channel], channel, &self.tempo_locks[channel], self.tempo.bpm)
+    }
+
+    fn sync_all_to_tempo(&mut self) -> Result<(), FxError> {
+        for channel in 0..CHANNELS {
+            self.sync_to_tempo(channel)?;
+        }
+        Ok(())
+    }
+
+    /// Json for one pedal (name + settings) on a board
+    fn pedal_json(&self, channel: usize, index: usize) -> Value {
+        self.boards[channel].as_json(channel)["effects"][index].clone()
+    }
+
+    /// Acknowledgement for a command that carried a request id
+    fn ack(&self, request_id: u64, result: Result<(), FxError>) -> Value {
+        let error = match &result {
+            Ok(()) => Value::Null,
+            Err(e) => json!(e),
+        };
+        json!({
+            "ackEvent": {
+                "requestId": request_id,
+                "ok": result.is_ok(),
+                "error": error,
+                "pedalInfo": self.board_config()["pedalInfo"],
             }
+        })
+    }
+
+    /// Hand the boards and mixer to the session writer
+    fn save_session(&mut self) {
+        let snapshot = SessionSnapshot {
+            boards: [
+                self.boards[0].as_json(0)["effects"].clone(),
+                self.boards[1].as_json(1)["effects"].clone(),
+            ],
+            mixer: self.mixer,
+            buses: [
+                self.buses[0].as_json(0)["effects"].clone(),
+                self.buses[1].as_json(1)["effects"].clone(),
+            ],
+            bpm: self.tempo.bpm,
+            time_signature: self.tempo.time_signature,
+            tempo_locks: self.tempo_locks.clone(),
+        };
+        if let Err(e) = self.session_tx.send(snapshot) {
+            error!("failed to send session: {}", e);
         }
+        self.session_dirty = false;
     }
 
     pub fn levels(&mut self) -> Value {
@@ -193,20 +679,62 @@ impl BoardSet {
                 },
                 "leftFreq": self.tuners[0].get_note(),
                 "rightFreq": self.tuners[1].get_note(),
+                "masterLoudness": self.loudness.as_json(),
+                "looper": self.looper.as_json(),
             }
         })
     }
     pub fn board_config(&self) -> Value {
         json!({
-            "pedalTypes": PedalBoard::get_pedal_types(),
+            "pedalTypes": StereoBoard::get_pedal_types(),
             "pedalInfo": [
                 self.boards[0].as_json(0),
                 self.boards[1].as_json(1),
-            ]
+            ],
+            "history": [
+                self.history[0].as_json(),
+                self.history[1].as_json(),
+            ],
+            "buses": [
+                self.buses[0].as_json(0),
+                self.buses[1].as_json(1),
+            ],
+            "tempoLocks": self.tempo_locks,
+            "metronome": self.metronome.settings(),
         })
     }
 }
 
+fn check_channel(channel: usize) -> Result<(), FxError> {
+    if channel >= CHANNELS {
+        return Err(FxError::IndexOutOfRange(format!("channel {}", channel)));
+    }
+    Ok(())
+}
+
+fn check_bus(bus: usize) -> Result<(), FxError> {
+    if bus >= BUSES {
+        return Err(FxError::IndexOutOfRange(format!("bus {}", bus)));
+    }
+    Ok(())
+}
+
+fn check_index(index: usize, len: usize) -> Result<(), FxError> {
+    if index >= len {
+        return Err(FxError::IndexOutOfRange(format!("pedal index {} (board has {})", index, len)));
+    }
+    Ok(())
+}
+
+/// Put the time based pedals on a board in time, if the board is locked
+fn sync_board(board: &mut StereoBoard, channel: usize, lock: &TempoLock, bpm: f64) -> Result<(), FxError> {
+    let config = board.as_json(channel);
+    for (pedal, setting) in lock.settings(&config["effects"], bpm)? {
+        board.change_value(pedal, &setting);
+    }
+    Ok(())
+}
+
 // By implementing the Callback trait (defined in alsa_device) this structure can
 // be passed into the process_a_frame function on the alsa device.  The alsa device will
 // call the function named "call" with a frame of audio samples.
@@ -219,12 +747,28 @@ impl SoundCallback for BoardSet {
         // Push a frame of data into the system
         self.tuners[0].add_samples(in_a);
         self.tuners[1].add_samples(in_b);
+        self.scope.tap(ScopeTap::Input, 0).add_frame(in_a);
+        self.scope.tap(ScopeTap::Input, 1).add_frame(in_b);
         self.input_meters[0].add_frame(in_a, 1.0);
         self.input_meters[1].add_frame(in_b, 1.0);
-        self.boards[0].process(in_a, &mut self.output_buffers[0]);
-        self.boards[1].process(in_b, &mut self.output_buffers[1]);
-        self.output_meters[0].add_frame(&self.output_buffers[0], 1.0);    
-        self.output_meters[1].add_frame(&self.output_buffers[1], 1.0);
+        let [[left_0, right_0], [left_1, right_1]] = &mut self.output_buffers;
+        self.boards[0].process(in_a, left_0, right_0);
+        self.boards[1].process(in_b, left_1, right_1);
+        self.recorder.add_frame([in_a, in_b, left_0, right_0, left_1, right_1]);
+        // Meters and scope see both sides of a stereo board, a mono board is the same on each
+        for channel in 0..CHANNELS {
+            let [left, right] = &self.output_buffers[channel];
+            let output: &[f32] = if self.boards[channel].is_stereo() {
+                for (mid, (l, r)) in self.mid_buffer.iter_mut().zip(left.iter().zip(right.iter())) {
+                    *mid = (l + r) / 2.0;
+                }
+                &self.mid_buffer
+            } else {
+                left
+            };
+            self.output_meters[channel].add_frame(output, 1.0);
+            self.scope.tap(ScopeTap::Output, channel).add_frame(output);
+        }
         // Check if we need to send a latency update
         let now = get_micro_time();
         if self.update_timer.expired(now) {
@@ -238,15 +782,64 @@ impl SoundCallback for BoardSet {
                 }
             }
         }
+        if self.session_dirty && self.session_timer.expired(now) {
+            self.save_session();
+        }
 
     }
     fn get_playback_data(&mut self, out_a: &mut [f32], out_b: &mut [f32]) -> () {
+        // A board looper sits before the mixer so the channel fader still applies
+        if let Some(channel) = self.looper.channel {
+            let [left, right] = &mut self.output_buffers[channel];
+            for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+                (*l, *r) = self.looper.tick(*l, *r);
+            }
+        }
+        // Feed the aux buses from the boards after their faders
+        for (bus, board) in self.buses.iter_mut().enumerate() {
+            let [left, right] = &mut self.bus_buffers[bus];
+            // An empty bus would return the dry send into the master, and with every send off
+            // there is nothing to run
+            if board.is_empty() || !self.mixer.sends.iter().any(|sends| sends[bus].on) {
+                left.fill(0.0);
+                right.fill(0.0);
+                continue;
+            }
+            let sends = [self.mixer.send_gain(0, bus), self.mixer.send_gain(1, bus)];
+            for (i, sample) in self.send_buffer.iter_mut().enumerate() {
+                *sample = sends
+                    .iter()
+                    .zip(self.output_buffers.iter())
+                    .map(|(send, [left, right])| send * (left[i] + right[i]) / 2.0)
+                    .sum();
+            }
+            board.process(&self.send_buffer, left, right);
+        }
+        let gains = [
+            self.mixer.output_matrix(0, self.boards[0].is_stereo()),
+            self.mixer.output_matrix(1, self.boards[1].is_stereo()),
+        ];
+        let returns = [self.mixer.return_matrix(0), self.mixer.return_matrix(1)];
         let mut i: usize = 0;
         while i < FRAME_SIZE {
-            out_a[i] = self.output_buffers[0][i] + self.output_buffers[1][i];
-            out_b[i] = self.output_buffers[0][i] + self.output_buffers[1][i];
+            out_a[i] = 0.0;
+            out_b[i] = 0.0;
+            let sources = gains.iter().zip(self.output_buffers.iter()).chain(returns.iter().zip(self.bus_buffers.iter()));
+            for (gain, [left, right]) in sources {
+                out_a[i] += gain[0][0] * left[i] + gain[0][1] * right[i];
+                out_b[i] += gain[1][0] * left[i] + gain[1][1] * right[i];
+            }
+            if self.looper.channel.is_none() {
+                (out_a[i], out_b[i]) = self.looper.tick(out_a[i], out_b[i]);
+            }
             i += 1;
         }
+        // The backing track goes on top of the loop so it isn't recorded into it
+        self.track.mix_into(out_a, out_b);
+        self.metronome.mix_into(&self.tempo, out_a, out_b);
+        self.scope.tap(ScopeTap::Master, 0).add_frame(out_a);
+        self.scope.tap(ScopeTap::Master, 1).add_frame(out_b);
+        self.loudness.add_frame(out_a, out_b);
     }
 
     /// This will let you know if the engine is still running
@@ -254,3 +847,92 @@ impl SoundCallback for BoardSet {
         self.running
     }
 }
+
+#[cfg(test)]
+mod test_board_set {
+    use super::*;
+
+    fn board_set() -> BoardSet {
+        let channel = Channel::new(|_| Ok(()));
+        let (_tx, rx_cmd) = mpsc::channel();
+        let (session_tx, _session_rx) = mpsc::channel();
+        let (_loop_memory_tx, loop_memory_rx) = mpsc::channel();
+        let (_recorder, record_tap) = Recorder::new(channel.clone());
+        let (_player, track_tap) = TrackPlayer::new(channel.clone());
+        BoardSet::new(channel, rx_cmd, session_tx, loop_memory_rx, SharedTempo::new(DEFAULT_BPM), record_tap, track_tap)
+    }
+
+    #[test]
+    fn capture_and_recall_scene() {
+        let mut board_set = board_set();
+        let split = json!({
+            "name": SPLIT,
+            "settings": [{ "name": "level a", "value": -3.0 }, { "name": "level b", "value": -9.0 }],
+            "branches": [[], []],
+        });
+        let mut mixer = MixerState::default();
+        mixer.channels[1].level = -12.0;
+        let recall = JamCommand::RecallScene { boards: [json!([]), json!([split])], mixer, buses: Default::default() };
+        board_set.execute(recall).unwrap();
+        let scene = board_set.execute(JamCommand::CaptureScene).unwrap().unwrap();
+        assert_eq!(scene["sceneEvent"]["mixer"], json!(mixer));
+        assert_eq!(scene["sceneEvent"]["boards"][0], json!([]));
+        assert_eq!(scene["sceneEvent"]["boards"][1][0]["name"], SPLIT);
+
+        // A bad board leaves the running scene alone
+        let bad = JamCommand::RecallScene {
+            boards: [json!([{ "name": "Delay" }]), json!([])],
+            mixer: MixerState::default(),
+            buses: Default::default(),
+        };
+        assert!(matches!(board_set.execute(bad), Err(FxError::InvalidPreset(_))));
+        let bad = JamCommand::RecallScene {
+            boards: [json!([]), json!([])],
+            mixer: MixerState::default(),
+            buses: [Value::Null, json!("reverb")],
+        };
+        assert!(matches!(board_set.execute(bad), Err(FxError::InvalidPreset(_))));
+        assert_eq!(board_set.execute(JamCommand::CaptureScene).unwrap().unwrap(), scene);
+    }
+
+    #[test]
+    fn buses_need_pedals_and_a_send() {
+        const REVERB: &str = "Sigma Reverb";
+        assert!(StereoBoard::get_pedal_types().get(REVERB).is_some());
+        let mut board_set = board_set();
+        let bad = JamCommand::LoadBus { bus: 0, board: json!([{ "name": REVERB }]) };
+        assert!(matches!(board_set.execute(bad), Err(FxError::InvalidPreset(_))));
+        board_set.mixer.sends[0][0].on = true;
+        board_set.output_buffers[0] = [vec![0.5; FRAME_SIZE], vec![0.5; FRAME_SIZE]];
+        let (mut out_a, mut out_b) = (vec![0.0; FRAME_SIZE], vec![0.0; FRAME_SIZE]);
+        // Run a few frames so a reverb has time to answer
+        let mut bus_level = |board_set: &mut BoardSet| {
+            (0..50)
+                .map(|_| {
+                    board_set.get_playback_data(&mut out_a, &mut out_b);
+                    board_set.bus_buffers[0].iter().flatten().map(|sample| sample.abs()).sum::<f32>()
+                })
+                .sum::<f32>()
+        };
+        // An empty bus doesn't return the dry send
+        assert_eq!(bus_level(&mut board_set), 0.0);
+
+        let reverb = board_set.buses[0].new_pedal(REVERB);
+        board_set.execute(JamCommand::LoadBus { bus: 0, board: json!([reverb]) }).unwrap();
+        assert!(bus_level(&mut board_set) > 0.0);
+
+        board_set.mixer.sends[0][0].on = false;
+        assert_eq!(bus_level(&mut board_set), 0.0);
+    }
+
+    #[test]
+    fn load_boards_is_all_or_nothing() {
+        let mut board_set = board_set();
+        let delay = json!({ "name": "Delay", "settings": [{ "name": "duration", "min": 2.0, "max": 500.0, "value": 100.0 }] });
+        board_set.tempo_locks[1].enabled = true;
+        board_set.tempo_locks[1].divisions.insert(String::from("Delay"), String::from("1/7q"));
+        let load = JamCommand::LoadBoards { boards: [json!([delay]), json!([delay])] };
+        assert!(board_set.execute(load).is_err());
+        assert_eq!(board_set.boards[0].as_json(0)["effects"], json!([]));
+    }
+}
@@ -0,0 +1,205 @@
+//! Error type used throughout the crate.
+//!
+//! Errors serialize to `{ "code": "...", "detail": "..." }` so the u/x can react to the
+//! code instead of matching on message text.
+
+use std::{fmt, num::{ParseFloatError, ParseIntError}, sync::mpsc::SendError};
+
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+
+#[derive(Debug)]
+pub enum FxError {
+    /// An alsa device could not be opened
+    DeviceOpen(String),
+    /// An alsa device refused the hardware/software parameters
+    HwParams(String),
+    /// Could not recover from an under/overrun
+    Xrun(String),
+    /// Any other alsa failure
+    Audio(String),
+    /// A command or config could not be parsed
+    ProtocolParse(String),
+    /// A pedal type that pedal-board does not know about
+    UnknownPedal(String),
+    /// A pedal setting that does not exist on the pedal
+    UnknownSetting(String),
+    /// A channel or pedal index past the end
+    IndexOutOfRange(String),
+    /// The audio engine has not been started (or has stopped)
+    EngineNotRunning,
+    /// The audio engine has already been started
+    EngineAlreadyRunning,
+    /// The audio thread did not answer a request in time
+    Timeout(String),
+    /// Could not deliver an event to the u/x
+    EventChannel(String),
+    /// A preset or export file that fails schema validation
+    InvalidPreset(String),
+    /// A stored item (preset, ...) that does not exist
+    NotFound(String),
+    /// File system or thread failure
+    Io(String),
+    /// An audio file that can't be opened or decoded
+    AudioFile(String),
+    /// A midi port is already open for this
+    MidiPortBusy(String),
+    /// No midi port is open for this
+    MidiPortClosed(String),
+    /// A take is already being recorded
+    AlreadyRecording,
+    /// There is no take to stop
+    NotRecording,
+    /// Too little disk space left to start or keep recording a take
+    RecordDiskFull { free_mb: u64 },
+    /// The track player lost its buffer when a feeder thread died
+    TrackPlayerFailed,
+}
+
+impl FxError {
+    /// Stable code for the u/x to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            FxError::DeviceOpen(_) => "device_open",
+            FxError::HwParams(_) => "hw_params",
+            FxError::Xrun(_) => "xrun",
+            FxError::Audio(_) => "audio",
+            FxError::ProtocolParse(_) => "protocol_parse",
+            FxError::UnknownPedal(_) => "unknown_pedal",
+            FxError::UnknownSetting(_) => "unknown_setting",
+            FxError::IndexOutOfRange(_) => "index_out_of_range",
+            FxError::EngineNotRunning => "engine_not_running",
+            FxError::EngineAlreadyRunning => "engine_already_running",
+            FxError::Timeout(_) => "timeout",
+            FxError::EventChannel(_) => "event_channel",
+            FxError::InvalidPreset(_) => "invalid_preset",
+            FxError::NotFound(_) => "not_found",
+            FxError::Io(_) => "io",
+            FxError::AudioFile(_) => "audio_file",
+            FxError::MidiPortBusy(_) => "midi_port_busy",
+            FxError::MidiPortClosed(_) => "midi_port_closed",
+            FxError::AlreadyRecording => "already_recording",
+            FxError::NotRecording => "not_recording",
+            FxError::RecordDiskFull { .. } => "record_disk_full",
+            FxError::TrackPlayerFailed => "track_player_failed",
+        }
+    }
+
+    pub fn detail(&self) -> String {
+        match self {
+            FxError::DeviceOpen(d)
+            | FxError::HwParams(d)
+            | FxError::Xrun(d)
+            | FxError::Audio(d)
+            | FxError::ProtocolParse(d)
+            | FxError::UnknownPedal(d)
+            | FxError::UnknownSetting(d)
+            | FxError::IndexOutOfRange(d)
+            | FxError::Timeout(d)
+            | FxError::EventChannel(d)
+            | FxError::InvalidPreset(d)
+            | FxError::NotFound(d)
+            | FxError::Io(d)
+            | FxError::AudioFile(d)
+            | FxError::MidiPortBusy(d)
+            | FxError::MidiPortClosed(d) => d.clone(),
+            FxError::EngineNotRunning => String::from("audio engine is not running"),
+            FxError::EngineAlreadyRunning => String::from("audio engine is already running"),
+            FxError::AlreadyRecording => String::from("a take is already recording"),
+            FxError::NotRecording => String::from("nothing is recording"),
+            FxError::RecordDiskFull { free_mb } => format!("only {} MB free for recording", free_mb),
+            FxError::TrackPlayerFailed => String::from("track player failed on an earlier track"),
+        }
+    }
+}
+
+impl fmt::Display for FxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.detail())
+    }
+}
+
+impl std::error::Error for FxError {}
+
+impl Serialize for FxError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("FxError", 2)?;
+        state.serialize_field("code", self.code())?;
+        state.serialize_field("detail", &self.detail())?;
+        state.end()
+    }
+}
+
+impl From<alsa::Error> for FxError {
+    fn from(e: alsa::Error) -> FxError {
+        FxError::Audio(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for FxError {
+    fn from(e: serde_json::Error) -> FxError {
+        FxError::ProtocolParse(e.to_string())
+    }
+}
+
+impl From<ParseIntError> for FxError {
+    fn from(e: ParseIntError) -> FxError {
+        FxError::ProtocolParse(e.to_string())
+    }
+}
+
+impl From<ParseFloatError> for FxError {
+    fn from(e: ParseFloatError) -> FxError {
+        FxError::ProtocolParse(e.to_string())
+    }
+}
+
+impl From<tauri::Error> for FxError {
+    fn from(e: tauri::Error) -> FxError {
+        FxError::EventChannel(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for FxError {
+    fn from(e: std::io::Error) -> FxError {
+        FxError::Io(e.to_string())
+    }
+}
+
+impl From<hound::Error> for FxError {
+    fn from(e: hound::Error) -> FxError {
+        FxError::Io(e.to_string())
+    }
+}
+
+impl From<symphonia::core::errors::Error> for FxError {
+    fn from(e: symphonia::core::errors::Error) -> FxError {
+        FxError::AudioFile(e.to_string())
+    }
+}
+
+// The receiving end of a command channel only goes away when the audio thread exits
+impl<T> From<SendError<T>> for FxError {
+    fn from(_e: SendError<T>) -> FxError {
+        FxError::EngineNotRunning
+    }
+}
+
+#[cfg(test)]
+mod test_error {
+    use super::*;
+
+    #[test]
+    fn serializes_code_and_detail() {
+        let e = FxError::UnknownPedal(String::from("Kazoo"));
+        assert_eq!(
+            serde_json::to_value(&e).unwrap(),
+            serde_json::json!({ "code": "unknown_pedal", "detail": "Kazoo" })
+        );
+        assert_eq!(serde_json::to_value(FxError::EngineNotRunning).unwrap()["code"], "engine_not_running");
+        assert_eq!(FxError::MidiPortBusy(String::from("midi input is already open")).code(), "midi_port_busy");
+        assert_eq!(
+            serde_json::to_value(FxError::RecordDiskFull { free_mb: 12 }).unwrap(),
+            serde_json::json!({ "code": "record_disk_full", "detail": "only 12 MB free for recording" })
+        );
+    }
+}
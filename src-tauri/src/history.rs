@@ -0,0 +1,234 @@
+//! Undo/redo history for board edits.
+//!
+//! Every edit made to a board records enough to replay it in either direction.  Undo
+//! applies the inverse operation, redo applies the edit again.  The history is bounded
+//! and is cleared whenever a whole board is loaded.
+
+use std::{collections::VecDeque, time::{Duration, Instant}};
+
+use serde_json::{json, Value};
+
+use crate::{stereo_board::StereoBoard, tempo::TempoLock};
+
+/// Edits kept per channel
+pub const MAX_HISTORY: usize = 50;
+/// Changes of one setting closer together than this are one edit
+pub const MERGE_WINDOW: Duration = Duration::from_millis(500);
+
+/// The board's tempo lock before and after an edit that set a note division
+#[derive(Clone, Debug, PartialEq)]
+pub struct LockChange {
+    pub before: TempoLock,
+    pub after: TempoLock,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BoardEdit {
+    /// pedal is the pedal json (name + settings) as it was inserted
+    Insert { index: usize, pedal: Value },
+    /// pedal is the pedal json as it was before being deleted
+    Delete { index: usize, pedal: Value },
+    Move { from: usize, to: usize },
+    /// setting and previous are `{ "name": ..., "value": ... }`.  lock is set when the
+    /// value was a note division that changed the tempo lock.
+    SetValue { pedal: usize, setting: Value, previous: Value, lock: Option<LockChange> },
+    /// The whole pedal json before and after, used for edits inside a split's branches
+    Replace { index: usize, before: Value, after: Value },
+}
+
+impl BoardEdit {
+    pub fn undo(&self, board: &mut StereoBoard, tempo_lock: &mut TempoLock) {
+        match self {
+            BoardEdit::Insert { index, .. } => board.delete_pedal(*index),
+            BoardEdit::Delete { index, pedal } => board.insert_json(*index, pedal),
+            BoardEdit::Move { from, to } => board.move_pedal(*to, *from),
+            BoardEdit::SetValue { pedal, previous, lock, .. } => {
+                board.change_value(*pedal, previous);
+                if let Some(lock) = lock {
+                    *tempo_lock = lock.before.clone();
+                }
+            }
+            BoardEdit::Replace { index, before, .. } => board.replace(*index, before),
+        }
+    }
+
+    pub fn redo(&self, board: &mut StereoBoard, tempo_lock: &mut TempoLock) {
+        match self {
+            BoardEdit::Insert { index, pedal } => board.insert_json(*index, pedal),
+            BoardEdit::Delete { index, .. } => board.delete_pedal(*index),
+            BoardEdit::Move { from, to } => board.move_pedal(*from, *to),
+            BoardEdit::SetValue { pedal, setting, lock, .. } => {
+                board.change_value(*pedal, setting);
+                if let Some(lock) = lock {
+                    *tempo_lock = lock.after.clone();
+                }
+            }
+            BoardEdit::Replace { index, after, .. } => board.replace(*index, after),
+        }
+    }
+}
+
+pub struct EditHistory {
+    undo: VecDeque<BoardEdit>,
+    redo: Vec<BoardEdit>,
+    /// When the last edit was recorded, if a new one could still merge into it
+    last_edit: Option<Instant>,
+}
+
+impl EditHistory {
+    pub fn new() -> EditHistory {
+        EditHistory {
+            undo: VecDeque::with_capacity(MAX_HISTORY),
+            redo: Vec::with_capacity(MAX_HISTORY),
+            last_edit: None,
+        }
+    }
+
+    /// Record a new edit.  This drops anything that could have been redone.
+    ///
+    /// Back to back changes of the same setting within `MERGE_WINDOW` of each other (a
+    /// knob being turned, an expression pedal on a CC) are one edit, so undo goes back to
+    /// where the sweep started.
+    pub fn record(&mut self, edit: BoardEdit, now: Instant) {
+        self.redo.clear();
+        let recent = self
+            .last_edit
+            .replace(now)
+            .is_some_and(|last| now.saturating_duration_since(last) <= MERGE_WINDOW);
+        if let (
+            true,
+            BoardEdit::SetValue { pedal, setting, lock, .. },
+            Some(BoardEdit::SetValue { pedal: last, setting: last_setting, lock: last_lock, .. }),
+        ) = (recent, &edit, self.undo.back_mut())
+        {
+            if pedal == last && setting["name"] == last_setting["name"] {
+                *last_setting = setting.clone();
+                match (last_lock.as_mut(), lock) {
+                    (Some(last_lock), Some(lock)) => last_lock.after = lock.after.clone(),
+                    (None, Some(lock)) => *last_lock = Some(lock.clone()),
+                    _ => {}
+                }
+                return;
+            }
+        }
+        if self.undo.len() == MAX_HISTORY {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(edit);
+    }
+
+    /// Take the most recent edit so it can be undone
+    pub fn undo(&mut self) -> Option<BoardEdit> {
+        let edit = self.undo.pop_back()?;
+        self.last_edit = None;
+        self.redo.push(edit.clone());
+        Some(edit)
+    }
+
+    /// Take the most recently undone edit so it can be applied again
+    pub fn redo(&mut self) -> Option<BoardEdit> {
+        let edit = self.redo.pop()?;
+        self.last_edit = None;
+        self.undo.push_back(edit.clone());
+        Some(edit)
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.last_edit = None;
+    }
+
+    pub fn as_json(&self) -> Value {
+        json!({
+            "undo": self.undo.len(),
+            "redo": self.redo.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_history {
+    use super::*;
+
+    fn moved(n: usize) -> BoardEdit {
+        BoardEdit::Move { from: n, to: 0 }
+    }
+
+    #[test]
+    fn undo_redo_order() {
+        let mut history = EditHistory::new();
+        let now = Instant::now();
+        history.record(moved(1), now);
+        history.record(moved(2), now);
+        assert_eq!(history.undo(), Some(moved(2)));
+        assert_eq!(history.undo(), Some(moved(1)));
+        assert_eq!(history.undo(), None);
+        assert_eq!(history.redo(), Some(moved(1)));
+        assert_eq!(history.as_json(), json!({ "undo": 1, "redo": 1 }));
+        // A new edit throws away the redo stack
+        history.record(moved(3), now);
+        assert_eq!(history.redo(), None);
+    }
+    #[test]
+    fn sweeps_are_one_edit() {
+        let set = |pedal: usize, name: &str, value: f64, previous: f64| BoardEdit::SetValue {
+            pedal,
+            setting: json!({ "name": name, "value": value }),
+            previous: json!({ "name": name, "value": previous }),
+            lock: None,
+        };
+        let mut history = EditHistory::new();
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        for n in 0..MAX_HISTORY * 2 {
+            history.record(set(1, "gain", n as f64 + 1.0, n as f64), at(n as u64 * 20));
+        }
+        let end = MAX_HISTORY as u64 * 40;
+        history.record(set(1, "level", 2.0, 1.0), at(end));
+        history.record(set(0, "level", 2.0, 1.0), at(end));
+        // The same setting again, but after a pause
+        history.record(set(0, "level", 3.0, 2.0), at(end + 2000));
+        assert_eq!(history.as_json()["undo"], 4);
+        history.undo();
+        history.undo();
+        history.undo();
+        assert_eq!(history.undo(), Some(set(1, "gain", (MAX_HISTORY * 2) as f64, 0.0)));
+    }
+    #[test]
+    fn undo_puts_the_tempo_lock_back() {
+        let before = TempoLock::default();
+        let mut after = before.clone();
+        after.divisions.insert(String::from("Delay"), String::from("1/8d"));
+        let set = |value: f64, lock: Option<LockChange>| BoardEdit::SetValue {
+            pedal: 0,
+            setting: json!({ "name": "duration", "value": value }),
+            previous: json!({ "name": "duration", "value": 100.0 }),
+            lock,
+        };
+        // A division set in the middle of a sweep still comes back out with it
+        let mut history = EditHistory::new();
+        let now = Instant::now();
+        history.record(set(120.0, None), now);
+        history.record(set(225.0, Some(LockChange { before: before.clone(), after: after.clone() })), now);
+        let edit = history.undo().unwrap();
+        let (mut board, mut lock) = (StereoBoard::new(0), after.clone());
+        edit.undo(&mut board, &mut lock);
+        assert_eq!(lock, before);
+        edit.redo(&mut board, &mut lock);
+        assert_eq!(lock, after);
+    }
+    #[test]
+    fn bounded() {
+        let mut history = EditHistory::new();
+        for n in 0..MAX_HISTORY + 10 {
+            history.record(moved(n), Instant::now());
+        }
+        assert_eq!(history.as_json()["undo"], MAX_HISTORY);
+        let mut last = None;
+        while let Some(edit) = history.undo() {
+            last = Some(edit);
+        }
+        assert_eq!(last, Some(moved(10)));
+    }
+}
@@ -1,65 +1,609 @@
 #[macro_use]
 extern crate num_derive;
 
-use log::info;
-use param_message::ParamMessage;
-use serde_json::Value;
-use std::sync::Mutex;
+use alsa_thread::CHANNELS;
+use board_diff::{BoardDiff, BoardSource};
+use log::{debug, error, info};
+use midi::{MidiClockOut, MidiEvent, MidiInput, MidiMessageType, MidiPort};
+use midi_map::{CcMapping, CcTarget, MidiMap, MidiMapping};
+use program_map::{ProgramMap, ProgramMapStore, ProgramTarget};
+use param_message::{CommandRequest, JamCommand};
+use presets::{Preset, PresetInput, PresetStore, PresetSummary};
+use scenes::{Scene, SceneStore};
+use session::{Session, SessionStore};
+use tempo::{MidiClockIn, TempoSource};
+use track_player::Transport;
+use setlists::{Setlist, SetlistPlayer, SetlistSong, SetlistStore, Setlists};
+use serde_json::{json, Value};
+use std::{path::PathBuf, sync::Mutex, time::Instant};
 
-use tauri::{ipc::Channel, State};
+use tauri::{ipc::Channel, AppHandle, Manager, State};
 
 mod alsa_thread;
-mod box_error;
+mod looper;
+mod loudness;
+mod metronome;
+mod midi;
+mod midi_map;
+mod mixer;
+mod board_diff;
 mod board_set;
+mod error;
+mod history;
 mod utils;
 mod param_message;
+mod presets;
+mod program_map;
+mod recorder;
+mod preset_schema;
+mod scenes;
+mod scope;
+mod session;
+mod setlists;
+mod stereo_board;
+mod store;
+mod tempo;
+mod track_player;
 
 use board_set::BoardConnection;
+use error::FxError;
 
 struct UnitState(Mutex<BoardConnection>);
+struct PresetState(Mutex<PresetStore>);
+struct SetlistState(Mutex<Setlists>);
+struct SceneState(Mutex<SceneStore>);
+struct SessionState(SessionStore);
+struct MidiState(Mutex<MidiInput>);
+struct MidiClockOutState(Mutex<MidiClockOut>);
+struct MidiMapState(Mutex<MidiMapping>);
+struct ProgramMapState(Mutex<ProgramMapStore>);
+struct RecordingDir(PathBuf);
 
+/// Start the engine.  Devices default to the ones used last time and the last
+/// session's boards and mixer are restored unless auto-restore is off.
 #[tauri::command]
 fn start(
     unit_state: State<'_, UnitState>,
+    session_state: State<'_, SessionState>,
     on_event: Channel<Value>,
-    in_dev: String,
-    out_dev: String
-) -> Result<(), String> {
+    in_dev: Option<String>,
+    out_dev: Option<String>
+) -> Result<(), FxError> {
     info!("Starting board set");
+    let store = session_state.0.clone();
+    // The session only fills in what wasn't given, so it can't keep the engine from starting
+    let session = store.load().unwrap_or_else(|e| {
+        error!("last session can't be loaded: {}", e);
+        Session::default()
+    });
+    let no_device = |which: &str| FxError::DeviceOpen(format!("no {} device given and none saved", which));
+    let in_dev = in_dev.or(session.in_dev.clone()).ok_or_else(|| no_device("input"))?;
+    let out_dev = out_dev.or(session.out_dev.clone()).ok_or_else(|| no_device("output"))?;
+    // Anything that can fail happens before the engine starts, otherwise a retry would
+    // only get EngineAlreadyRunning
+    if let Err(e) = store.update(|s| {
+        s.in_dev = Some(in_dev.clone());
+        s.out_dev = Some(out_dev.clone());
+    }) {
+        error!("devices can't be saved to the session: {}", e);
+    }
+    let restore = if session.auto_restore && session.has_boards() {
+        restore_commands(session).unwrap_or_else(|e| {
+            error!("last session can't be restored: {}", e);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
     let mut board_con = unit_state.0.lock().unwrap();
-    match board_con.start(on_event, in_dev, out_dev) {
-        Ok(()) => { Ok(()) }
-        Err(e) => { Err(e.to_string()) }
+    board_con.start(on_event, in_dev, out_dev, store)?;
+    if !restore.is_empty() {
+        info!("Restoring last session");
+    }
+    for command in restore {
+        if let Err(e) = board_con.send_command(CommandRequest::new(command)) {
+            error!("session restore command failed: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// The commands that put a saved session back on the engine
+fn restore_commands(session: Session) -> Result<Vec<JamCommand>, FxError> {
+    let Session { bpm, time_signature, tempo_locks, boards, mixer, buses, .. } = session;
+    let [board_a, board_b] = boards;
+    let boards = [preset_schema::upgrade_config(board_a)?, preset_schema::upgrade_config(board_b)?];
+    let [bus_a, bus_b] = buses;
+    let buses = [preset_schema::upgrade_config(bus_a)?, preset_schema::upgrade_config(bus_b)?];
+    let mut commands = vec![
+        JamCommand::SetTempo { bpm, source: TempoSource::Manual },
+        JamCommand::SetTimeSignature { beats: time_signature.beats, unit: time_signature.unit },
+    ];
+    for (channel, lock) in tempo_locks.into_iter().enumerate() {
+        commands.push(JamCommand::SetTempoLock { channel, lock });
     }
+    commands.push(JamCommand::RecallScene { boards, mixer, buses });
+    // Let the u/x see the restored boards
+    commands.push(JamCommand::GetConfigJson);
+    Ok(commands)
 }
 
 #[tauri::command]
-fn stop(unit_state: State<'_, UnitState>) -> Result<(), String> {
+fn stop(unit_state: State<'_, UnitState>) -> Result<(), FxError> {
     info!("Stopping board set");
     let mut board_con = unit_state.0.lock().unwrap();
-    match board_con.stop() {
-        Ok(()) => { Ok(()) }
-        Err(e) => { Err(e.to_string()) }
-    }
+    board_con.stop()
 }
 
 
 
 #[tauri::command]
-fn commandmsg(unit_state: State<'_, UnitState>, msg: Value) -> Result<(), String> {
+fn commandmsg(unit_state: State<'_, UnitState>, msg: Value) -> Result<(), FxError> {
     info!("Sending command to board set {}", msg);
+    let mut request = CommandRequest::from_json(&msg)?;
+    // Old board configs need upgrading before pedal-board sees them
+    match &mut request.command {
+        JamCommand::LoadBoard { board, .. } | JamCommand::LoadBus { board, .. } => {
+            *board = preset_schema::upgrade_config(board.take())?;
+            preset_schema::validate_config(board)?;
+        }
+        JamCommand::LoadBoards { boards } => {
+            for board in boards.iter_mut().filter(|board| !board.is_null()) {
+                *board = preset_schema::upgrade_config(board.take())?;
+                preset_schema::validate_config(board)?;
+            }
+        }
+        _ => {}
+    }
     let mut board_con = unit_state.0.lock().unwrap();
-    match ParamMessage::from_json(&msg) {
-        Ok(param_message) => {
-            match board_con.send_command(param_message)  {
-                Ok(()) => { Ok(()) }
-                Err(e) => { Err(e.to_string()) }
+    board_con.send_command(request)
+}
+
+#[tauri::command]
+fn list_presets(preset_state: State<'_, PresetState>) -> Result<Vec<PresetSummary>, FxError> {
+    preset_state.0.lock().unwrap().list()
+}
+
+#[tauri::command]
+fn get_preset(preset_state: State<'_, PresetState>, id: u64) -> Result<Preset, FxError> {
+    preset_state.0.lock().unwrap().get(id)
+}
+
+#[tauri::command]
+fn save_preset(preset_state: State<'_, PresetState>, preset: PresetInput) -> Result<Preset, FxError> {
+    info!("Saving preset {}", preset.name);
+    preset_state.0.lock().unwrap().save(preset)
+}
+
+#[tauri::command]
+fn rename_preset(preset_state: State<'_, PresetState>, id: u64, name: String) -> Result<Preset, FxError> {
+    preset_state.0.lock().unwrap().rename(id, &name)
+}
+
+#[tauri::command]
+fn delete_preset(preset_state: State<'_, PresetState>, id: u64) -> Result<(), FxError> {
+    info!("Deleting preset {}", id);
+    preset_state.0.lock().unwrap().delete(id)
+}
+
+#[tauri::command]
+fn duplicate_preset(preset_state: State<'_, PresetState>, id: u64, name: Option<String>) -> Result<Preset, FxError> {
+    preset_state.0.lock().unwrap().duplicate(id, name)
+}
+
+#[tauri::command]
+fn export_preset(preset_state: State<'_, PresetState>, id: u64) -> Result<Value, FxError> {
+    let preset = preset_state.0.lock().unwrap().get(id)?;
+    Ok(preset_schema::export_board(&preset))
+}
+
+/// Export the given presets (or the whole library) as a bank
+#[tauri::command]
+fn export_bank(preset_state: State<'_, PresetState>, ids: Option<Vec<u64>>) -> Result<Value, FxError> {
+    let store = preset_state.0.lock().unwrap();
+    let ids = match ids {
+        Some(ids) => ids,
+        None => store.list()?.into_iter().map(|p| p.id).collect(),
+    };
+    let mut presets = Vec::new();
+    for id in ids {
+        presets.push(store.get(id)?);
+    }
+    Ok(preset_schema::export_bank(&presets))
+}
+
+/// Import a board or bank export (any schema version) into the library
+#[tauri::command]
+fn import_presets(preset_state: State<'_, PresetState>, data: Value) -> Result<Vec<Preset>, FxError> {
+    let presets = preset_schema::import(data)?;
+    info!("Importing {} presets", presets.len());
+    preset_state.0.lock().unwrap().import(presets)
+}
+
+#[tauri::command]
+fn list_setlists(setlist_state: State<'_, SetlistState>) -> Result<Vec<Setlist>, FxError> {
+    setlist_state.0.lock().unwrap().store.list()
+}
+
+#[tauri::command]
+fn save_setlist(setlist_state: State<'_, SetlistState>, setlist: Setlist) -> Result<Setlist, FxError> {
+    info!("Saving setlist {}", setlist.name);
+    let mut setlists = setlist_state.0.lock().unwrap();
+    let saved = setlists.store.save(setlist)?;
+    setlists.player.refresh(&saved);
+    Ok(saved)
+}
+
+#[tauri::command]
+fn delete_setlist(setlist_state: State<'_, SetlistState>, id: u64) -> Result<(), FxError> {
+    setlist_state.0.lock().unwrap().store.delete(id)
+}
+
+// Load the presets for a song onto both channels and tell the u/x where we are
+fn load_song(
+    unit_state: &UnitState,
+    preset_state: &PresetState,
+    song: &SetlistSong,
+) -> Result<(), FxError> {
+    info!("Loading song {}", song.name);
+    let mut boards: [Value; CHANNELS] = Default::default();
+    {
+        // Don't hold the presets while waiting on the engine
+        let presets = preset_state.0.lock().unwrap();
+        for (board, id) in boards.iter_mut().zip(song.presets.iter()) {
+            if let Some(id) = id {
+                *board = presets.get(*id)?.config;
+            }
+        }
+    }
+    // Both channels change at the same frame
+    unit_state.0.lock().unwrap().send_command(CommandRequest::new(JamCommand::LoadBoards { boards }))
+}
+
+/// Move the setlist with `step` and load the song it lands on.  The position only
+/// changes once the song has been sent to the engine.
+fn change_song(
+    unit_state: &UnitState,
+    preset_state: &PresetState,
+    setlists: &mut Setlists,
+    step: impl FnOnce(&mut SetlistPlayer) -> Result<SetlistSong, FxError>,
+) -> Result<(), FxError> {
+    let mut player = setlists.player.clone();
+    let song = step(&mut player)?;
+    load_song(unit_state, preset_state, &song)?;
+    setlists.player = player;
+    unit_state.0.lock().unwrap().send_event(setlists.player.as_json())
+}
+
+#[tauri::command]
+fn select_setlist(
+    unit_state: State<'_, UnitState>,
+    preset_state: State<'_, PresetState>,
+    setlist_state: State<'_, SetlistState>,
+    id: u64,
+) -> Result<(), FxError> {
+    let mut setlists = setlist_state.0.lock().unwrap();
+    let setlist = setlists.store.get(id)?;
+    change_song(&unit_state, &preset_state, &mut setlists, |player| player.select(setlist).cloned())
+}
+
+#[tauri::command]
+fn setlist_next(
+    unit_state: State<'_, UnitState>,
+    preset_state: State<'_, PresetState>,
+    setlist_state: State<'_, SetlistState>,
+) -> Result<(), FxError> {
+    let mut setlists = setlist_state.0.lock().unwrap();
+    change_song(&unit_state, &preset_state, &mut setlists, |player| player.next().cloned())
+}
+
+#[tauri::command]
+fn setlist_prev(
+    unit_state: State<'_, UnitState>,
+    preset_state: State<'_, PresetState>,
+    setlist_state: State<'_, SetlistState>,
+) -> Result<(), FxError> {
+    let mut setlists = setlist_state.0.lock().unwrap();
+    change_song(&unit_state, &preset_state, &mut setlists, |player| player.prev().cloned())
+}
+
+#[tauri::command]
+fn setlist_jump(
+    unit_state: State<'_, UnitState>,
+    preset_state: State<'_, PresetState>,
+    setlist_state: State<'_, SetlistState>,
+    position: usize,
+) -> Result<(), FxError> {
+    let mut setlists = setlist_state.0.lock().unwrap();
+    change_song(&unit_state, &preset_state, &mut setlists, |player| player.jump(position).cloned())
+}
+
+#[tauri::command]
+fn list_scenes(scene_state: State<'_, SceneState>) -> Result<Vec<Scene>, FxError> {
+    scene_state.0.lock().unwrap().list()
+}
+
+/// Snapshot both live boards, the bus chains and the mixer into a new scene
+#[tauri::command]
+fn capture_scene(
+    unit_state: State<'_, UnitState>,
+    scene_state: State<'_, SceneState>,
+    name: String,
+) -> Result<Scene, FxError> {
+    info!("Capturing scene {}", name);
+    let live = unit_state.0.lock().unwrap().request(JamCommand::CaptureScene)?;
+    let live = &live["sceneEvent"];
+    let scene = Scene {
+        id: 0,
+        name,
+        boards: [live["boards"][0].clone(), live["boards"][1].clone()],
+        mixer: serde_json::from_value(live["mixer"].clone())?,
+        buses: [live["buses"][0].clone(), live["buses"][1].clone()],
+    };
+    scene_state.0.lock().unwrap().save(scene)
+}
+
+#[tauri::command]
+fn recall_scene(unit_state: State<'_, UnitState>, scene_state: State<'_, SceneState>, id: u64) -> Result<(), FxError> {
+    let scene = scene_state.0.lock().unwrap().get(id)?;
+    send_scene(&mut unit_state.0.lock().unwrap(), scene)
+}
+
+fn send_scene(board_con: &mut BoardConnection, scene: Scene) -> Result<(), FxError> {
+    info!("Recalling scene {}", scene.name);
+    let [board_a, board_b] = scene.boards;
+    let boards = [preset_schema::upgrade_config(board_a)?, preset_schema::upgrade_config(board_b)?];
+    let [bus_a, bus_b] = scene.buses;
+    let buses = [preset_schema::upgrade_config(bus_a)?, preset_schema::upgrade_config(bus_b)?];
+    board_con.send_command(CommandRequest::new(JamCommand::RecallScene { boards, mixer: scene.mixer, buses }))
+}
+
+#[tauri::command]
+fn delete_scene(scene_state: State<'_, SceneState>, id: u64) -> Result<(), FxError> {
+    scene_state.0.lock().unwrap().delete(id)
+}
+
+// Get a board config from wherever the u/x pointed at
+fn resolve_board(unit_state: &UnitState, preset_state: &PresetState, source: BoardSource) -> Result<Value, FxError> {
+    match source {
+        BoardSource::Config { config } => preset_schema::upgrade_config(config),
+        BoardSource::Preset { id } => Ok(preset_state.0.lock().unwrap().get(id)?.config),
+        BoardSource::Live { channel } => {
+            let config = unit_state.0.lock().unwrap().request(JamCommand::GetConfigJson)?;
+            match config["pedalInfo"].get(channel) {
+                Some(board) => Ok(board["effects"].clone()),
+                None => Err(FxError::IndexOutOfRange(format!("channel {}", channel))),
+            }
+        }
+    }
+}
+
+/// What changed between two boards, e.g. a preset and the live board it was loaded onto
+#[tauri::command]
+fn diff_boards(
+    unit_state: State<'_, UnitState>,
+    preset_state: State<'_, PresetState>,
+    from: BoardSource,
+    to: BoardSource,
+) -> Result<BoardDiff, FxError> {
+    let from = resolve_board(&unit_state, &preset_state, from)?;
+    let to = resolve_board(&unit_state, &preset_state, to)?;
+    Ok(board_diff::diff_boards(&from, &to))
+}
+
+#[tauri::command]
+fn get_session(session_state: State<'_, SessionState>) -> Result<Session, FxError> {
+    session_state.0.load()
+}
+
+/// Turn restoring the last session on start on or off
+#[tauri::command]
+fn set_auto_restore(session_state: State<'_, SessionState>, enabled: bool) -> Result<Session, FxError> {
+    info!("Auto-restore {}", enabled);
+    session_state.0.update(|s| s.auto_restore = enabled)
+}
+
+// Everything that arrives on the MIDI input goes through here
+fn handle_midi(app: &AppHandle, clock: &mut MidiClockIn, event: MidiEvent) {
+    let unit_state = app.state::<UnitState>();
+    if event.is_system(midi::CLOCK) {
+        // Too many of these to forward, the u/x gets a tempoEvent instead
+        if let Some(bpm) = clock.pulse(Instant::now()) {
+            let mut board_con = unit_state.0.lock().unwrap();
+            let command = JamCommand::SetTempo { bpm, source: TempoSource::MidiClock };
+            if let Err(e) = board_con.send_command(CommandRequest::new(command)) {
+                debug!("midi clock tempo not applied: {}", e);
+            }
+        }
+        return;
+    }
+    if event.is_system(midi::START) || event.is_system(midi::CONTINUE) || event.is_system(midi::STOP) {
+        clock.reset();
+    }
+    if let Err(e) = unit_state.0.lock().unwrap().send_event(json!({ "midiEvent": event })) {
+        debug!("midi event not forwarded: {}", e);
+    }
+    match event.msg_type {
+        MidiMessageType::ControlChange => {
+            let midi_map_state = app.state::<MidiMapState>();
+            let mut midi_map = midi_map_state.0.lock().unwrap();
+            let mut board_con = unit_state.0.lock().unwrap();
+            if let Some(target) = midi_map.learn.take() {
+                if let Err(e) = learn_cc(&mut board_con, &mut midi_map, target, &event) {
+                    error!("midi learn failed: {}", e);
+                }
+            }
+            for command in midi_map.map.commands(&event) {
+                if let Err(e) = board_con.send_command(CommandRequest::new(command)) {
+                    debug!("midi mapping not applied: {}", e);
+                }
             }
         }
-        Err(e) => {
-            Err(e.to_string())
+        MidiMessageType::ProgramChange => {
+            if let Err(e) = program_change(app, &event) {
+                error!("program change {} failed: {}", event.note, e);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Recall whatever the program map has for a Program Change
+fn program_change(app: &AppHandle, event: &MidiEvent) -> Result<(), FxError> {
+    let target = app.state::<ProgramMapState>().0.lock().unwrap().map.lookup(event).cloned();
+    let target = match target {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+    info!("Program change {} on midi channel {}: {:?}", event.note, event.channel, target);
+    // The stores are read and let go before taking the unit lock, as in load_song
+    let unit_state = app.state::<UnitState>();
+    match target {
+        ProgramTarget::Preset { id, channel } => {
+            let board = app.state::<PresetState>().0.lock().unwrap().get(id)?.config;
+            let mut board_con = unit_state.0.lock().unwrap();
+            board_con.send_command(CommandRequest::new(JamCommand::LoadBoard { channel, board }))?;
+        }
+        ProgramTarget::Scene { id } => {
+            let scene = app.state::<SceneState>().0.lock().unwrap().get(id)?;
+            send_scene(&mut unit_state.0.lock().unwrap(), scene)?;
         }
     }
+    // The u/x did not ask for this change so tell it about the new boards
+    let mut board_con = unit_state.0.lock().unwrap();
+    board_con.send_command(CommandRequest::new(JamCommand::GetConfigJson))
+}
+
+// Bind a CC to the target that midi learn was waiting on
+fn learn_cc(
+    board_con: &mut BoardConnection,
+    midi_map: &mut MidiMapping,
+    target: CcTarget,
+    event: &MidiEvent,
+) -> Result<(), FxError> {
+    let config = board_con.request(JamCommand::GetConfigJson)?;
+    let mapping = CcMapping::learn(target, event, &config["pedalInfo"])?;
+    info!("Learned cc {} on midi channel {}", mapping.cc, mapping.midi_channel);
+    let mut map = midi_map.map.clone();
+    map.bind(mapping.clone());
+    midi_map.save(map)?;
+    board_con.send_event(json!({ "midiLearnEvent": mapping }))
+}
+
+#[tauri::command]
+fn list_midi_ports() -> Result<Vec<MidiPort>, FxError> {
+    midi::list_ports()
+}
+
+#[tauri::command]
+fn start_midi(app: AppHandle, midi_state: State<'_, MidiState>, port: String) -> Result<(), FxError> {
+    info!("Starting midi on {}", port);
+    let mut midi = midi_state.0.lock().unwrap();
+    let mut clock = MidiClockIn::new();
+    midi.start(&port, move |event| handle_midi(&app, &mut clock, event))
+}
+
+#[tauri::command]
+fn stop_midi(midi_state: State<'_, MidiState>) -> Result<(), FxError> {
+    info!("Stopping midi");
+    midi_state.0.lock().unwrap().stop()
+}
+
+/// Send MIDI clock following our tempo out of a port
+#[tauri::command]
+fn start_midi_clock_out(
+    unit_state: State<'_, UnitState>,
+    clock_state: State<'_, MidiClockOutState>,
+    port: String,
+) -> Result<(), FxError> {
+    let tempo = unit_state.0.lock().unwrap().tempo();
+    clock_state.0.lock().unwrap().start(&port, tempo)
+}
+
+#[tauri::command]
+fn stop_midi_clock_out(clock_state: State<'_, MidiClockOutState>) -> Result<(), FxError> {
+    clock_state.0.lock().unwrap().stop()
+}
+
+#[tauri::command]
+fn tap_tempo(unit_state: State<'_, UnitState>) -> Result<(), FxError> {
+    unit_state.0.lock().unwrap().send_command(CommandRequest::new(JamCommand::TapTempo))
+}
+
+#[tauri::command]
+fn get_midi_map(midi_map_state: State<'_, MidiMapState>) -> MidiMap {
+    midi_map_state.0.lock().unwrap().map.clone()
+}
+
+/// Replace the whole mapping table (used to edit ranges/curves and remove bindings)
+#[tauri::command]
+fn save_midi_map(midi_map_state: State<'_, MidiMapState>, map: MidiMap) -> Result<(), FxError> {
+    midi_map_state.0.lock().unwrap().save(map)
+}
+
+/// Bind the next CC that arrives to a pedal setting
+#[tauri::command]
+fn midi_learn(midi_map_state: State<'_, MidiMapState>, target: CcTarget) {
+    info!("Midi learn armed for {:?}", target);
+    midi_map_state.0.lock().unwrap().learn = Some(target);
+}
+
+#[tauri::command]
+fn cancel_midi_learn(midi_map_state: State<'_, MidiMapState>) {
+    midi_map_state.0.lock().unwrap().learn = None;
+}
+
+#[tauri::command]
+fn get_program_map(program_map_state: State<'_, ProgramMapState>) -> ProgramMap {
+    program_map_state.0.lock().unwrap().map.clone()
+}
+
+#[tauri::command]
+fn save_program_map(program_map_state: State<'_, ProgramMapState>, map: ProgramMap) -> Result<(), FxError> {
+    program_map_state.0.lock().unwrap().save(map)
+}
+
+/// Record the dry and wet stems of both channels.  `name` (usually the preset or song)
+/// goes in the file names along with the time.
+#[tauri::command]
+fn start_recording(
+    unit_state: State<'_, UnitState>,
+    recording_dir: State<'_, RecordingDir>,
+    name: Option<String>,
+) -> Result<Value, FxError> {
+    let name = name.unwrap_or_default();
+    info!("Start recording {}", name);
+    unit_state.0.lock().unwrap().start_recording(&recording_dir.0, &name)
+}
+
+#[tauri::command]
+fn stop_recording(unit_state: State<'_, UnitState>) -> Result<Value, FxError> {
+    info!("Stop recording");
+    unit_state.0.lock().unwrap().stop_recording()
+}
+
+/// Load a WAV, FLAC or MP3 to play along with (paused at the start)
+#[tauri::command]
+fn load_track(unit_state: State<'_, UnitState>, path: String) -> Result<Value, FxError> {
+    info!("Loading track {}", path);
+    unit_state.0.lock().unwrap().player()?.load(&PathBuf::from(path))
+}
+
+#[tauri::command]
+fn unload_track(unit_state: State<'_, UnitState>) -> Result<(), FxError> {
+    unit_state.0.lock().unwrap().player()?.unload()
+}
+
+/// Play, pause, seek or loop the backing track
+#[tauri::command]
+fn track_transport(unit_state: State<'_, UnitState>, transport: Transport) -> Result<Value, FxError> {
+    unit_state.0.lock().unwrap().player()?.transport(transport)
+}
+
+/// Backing track level in dB
+#[tauri::command]
+fn set_track_level(unit_state: State<'_, UnitState>, level: f32) -> Result<Value, FxError> {
+    Ok(unit_state.0.lock().unwrap().player()?.set_level(level))
 }
 
 #[tauri::command]
@@ -72,7 +616,69 @@ pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
         .manage(UnitState(Mutex::new(BoardConnection::new())))
-        .invoke_handler(tauri::generate_handler![greet, start, stop, commandmsg])
+        .manage(MidiState(Mutex::new(MidiInput::new())))
+        .manage(MidiClockOutState(Mutex::new(MidiClockOut::new())))
+        .setup(|app| {
+            let data_dir = app.path().app_data_dir()?;
+            app.manage(PresetState(Mutex::new(PresetStore::new(data_dir.join("presets"))?)));
+            app.manage(SetlistState(Mutex::new(Setlists {
+                store: SetlistStore::new(data_dir.join("setlists"))?,
+                player: SetlistPlayer::new(),
+            })));
+            app.manage(SceneState(Mutex::new(SceneStore::new(data_dir.join("scenes"))?)));
+            app.manage(SessionState(SessionStore::new(data_dir.join("session.json"))));
+            app.manage(ProgramMapState(Mutex::new(ProgramMapStore::new(data_dir.join("presets").join("program_map.json")))));
+            app.manage(MidiMapState(Mutex::new(MidiMapping::new(data_dir.join("midi_map.json")))));
+            app.manage(RecordingDir(data_dir.join("recordings")));
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![
+            greet,
+            start,
+            stop,
+            commandmsg,
+            list_presets,
+            get_preset,
+            save_preset,
+            rename_preset,
+            delete_preset,
+            duplicate_preset,
+            export_preset,
+            export_bank,
+            import_presets,
+            list_setlists,
+            save_setlist,
+            delete_setlist,
+            select_setlist,
+            setlist_next,
+            setlist_prev,
+            setlist_jump,
+            list_scenes,
+            capture_scene,
+            recall_scene,
+            delete_scene,
+            diff_boards,
+            get_session,
+            set_auto_restore,
+            list_midi_ports,
+            start_midi,
+            stop_midi,
+            start_midi_clock_out,
+            stop_midi_clock_out,
+            tap_tempo,
+            get_midi_map,
+            save_midi_map,
+            midi_learn,
+            cancel_midi_learn,
+            get_program_map,
+            save_program_map,
+            start_recording,
+            stop_recording,
+            load_track,
+            unload_track,
+            track_transport,
+            set_track_level,
+        ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
@@ -0,0 +1,306 @@
+//! Looper on the master bus (or on one board).
+//!
+//! Record the first pass, then play and overdub layers on top of it.  Undo swaps the
+//! loop with the copy taken before the last overdub, so a second undo brings the layer
+//! back.  With quantize on the loop length is rounded to whole bars of the global
+//! tempo when recording stops.
+//!
+//! The loop buffers are big, so they are allocated off the audio thread the first
+//! time the looper records (see [`LoopMemory`](LoopMemory)) and kept until the engine
+//! stops.  Nothing here allocates on the audio thread.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::alsa_thread::SAMPLE_RATE;
+
+/// Longest loop in seconds
+pub const MAX_LOOP_SECONDS: usize = 30;
+const MAX_LOOP: usize = MAX_LOOP_SECONDS * SAMPLE_RATE as usize;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum LooperState {
+    Empty,
+    Recording,
+    Playing,
+    Overdubbing,
+    Stopped,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum LooperAction {
+    /// Start recording, close the loop, or start an overdub (like a loop pedal footswitch)
+    Record,
+    /// Toggle overdubbing while playing
+    Overdub,
+    Play,
+    Stop,
+    /// Take off (or put back) the last overdub
+    Undo,
+    Clear,
+}
+
+impl LooperAction {
+    /// Index used by the legacy `JamParam::LooperControl` message
+    pub fn from_index(index: i64) -> Option<LooperAction> {
+        const ACTIONS: [LooperAction; 6] = [
+            LooperAction::Record,
+            LooperAction::Overdub,
+            LooperAction::Play,
+            LooperAction::Stop,
+            LooperAction::Undo,
+            LooperAction::Clear,
+        ];
+        usize::try_from(index).ok().and_then(|i| ACTIONS.get(i).copied())
+    }
+}
+
+/// The loop and its undo copy, both sides at the longest loop length
+pub struct LoopMemory {
+    buffer: [Vec<f32>; 2],
+    undo: [Vec<f32>; 2],
+}
+
+impl LoopMemory {
+    pub fn new() -> LoopMemory {
+        LoopMemory {
+            buffer: [vec![0.0; MAX_LOOP], vec![0.0; MAX_LOOP]],
+            undo: [vec![0.0; MAX_LOOP], vec![0.0; MAX_LOOP]],
+        }
+    }
+}
+
+pub struct Looper {
+    pub state: LooperState,
+    /// None loops the master output, otherwise the output of one board
+    pub channel: Option<usize>,
+    pub quantize: bool,
+    buffer: [Vec<f32>; 2],
+    undo: [Vec<f32>; 2],
+    can_undo: bool,
+    length: usize,
+    position: usize,
+    layers: usize,
+}
+
+impl Looper {
+    pub fn new() -> Looper {
+        Looper {
+            state: LooperState::Empty,
+            channel: None,
+            quantize: false,
+            buffer: [Vec::new(), Vec::new()],
+            undo: [Vec::new(), Vec::new()],
+            can_undo: false,
+            length: 0,
+            position: 0,
+            layers: 0,
+        }
+    }
+
+    /// True until the loop buffers have been handed over
+    pub fn needs_memory(&self) -> bool {
+        self.buffer[0].is_empty()
+    }
+
+    pub fn give_memory(&mut self, memory: LoopMemory) {
+        self.buffer = memory.buffer;
+        self.undo = memory.undo;
+    }
+
+    /// Run an action.  `bar` is the length of a bar in samples, used to quantize the
+    /// loop when recording stops.
+    pub fn action(&mut self, action: LooperAction, bar: usize) {
+        use LooperState::*;
+        match (action, self.state) {
+            (LooperAction::Record, Empty) => self.start_recording(),
+            (LooperAction::Record, Recording) | (LooperAction::Play, Recording) => self.close_loop(bar),
+            (LooperAction::Record, Playing) | (LooperAction::Overdub, Playing) => self.start_overdub(),
+            (LooperAction::Record, Overdubbing) | (LooperAction::Overdub, Overdubbing) => self.state = Playing,
+            (LooperAction::Record, Stopped) => {
+                self.position = 0;
+                self.start_overdub();
+            }
+            (LooperAction::Play, Stopped) => {
+                self.position = 0;
+                self.state = Playing;
+            }
+            (LooperAction::Stop, Recording) => {
+                self.close_loop(bar);
+                self.state = Stopped;
+            }
+            (LooperAction::Stop, Playing) | (LooperAction::Stop, Overdubbing) => self.state = Stopped,
+            (LooperAction::Undo, Playing) | (LooperAction::Undo, Overdubbing) | (LooperAction::Undo, Stopped) => self.undo(),
+            (LooperAction::Clear, _) => {
+                self.state = Empty;
+                self.length = 0;
+                self.position = 0;
+                self.layers = 0;
+                self.can_undo = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn start_recording(&mut self) {
+        // Nowhere to record to yet
+        if self.needs_memory() {
+            return;
+        }
+        self.length = 0;
+        self.position = 0;
+        self.layers = 1;
+        self.can_undo = false;
+        self.state = LooperState::Recording;
+    }
+
+    fn close_loop(&mut self, bar: usize) {
+        let recorded = self.position;
+        let mut length = recorded;
+        if self.quantize && bar > 0 {
+            let bars = ((recorded + bar / 2) / bar).clamp(1, (MAX_LOOP / bar).max(1));
+            length = (bars * bar).min(MAX_LOOP);
+        }
+        // Anything past what was recorded is silence
+        if length > recorded {
+            for buf in self.buffer.iter_mut() {
+                buf[recorded..length].fill(0.0);
+            }
+        }
+        self.length = length;
+        self.position = 0;
+        self.state = if length == 0 { LooperState::Empty } else { LooperState::Playing };
+    }
+
+    fn start_overdub(&mut self) {
+        for (undo, buf) in self.undo.iter_mut().zip(self.buffer.iter()) {
+            undo[..self.length].copy_from_slice(&buf[..self.length]);
+        }
+        self.can_undo = true;
+        self.layers += 1;
+        self.state = LooperState::Overdubbing;
+    }
+
+    fn undo(&mut self) {
+        if !self.can_undo {
+            return;
+        }
+        std::mem::swap(&mut self.buffer, &mut self.undo);
+        // Swapping again is a redo
+        if self.state == LooperState::Overdubbing {
+            self.state = LooperState::Playing;
+        }
+    }
+
+    /// Process one stereo sample: records it and returns it with the loop added
+    pub fn tick(&mut self, left: f32, right: f32) -> (f32, f32) {
+        match self.state {
+            LooperState::Recording => {
+                self.buffer[0][self.position] = left;
+                self.buffer[1][self.position] = right;
+                self.position += 1;
+                if self.position == MAX_LOOP {
+                    self.close_loop(0);
+                }
+                (left, right)
+            }
+            LooperState::Playing | LooperState::Overdubbing => {
+                let p = self.position;
+                let out = (left + self.buffer[0][p], right + self.buffer[1][p]);
+                if self.state == LooperState::Overdubbing {
+                    self.buffer[0][p] += left;
+                    self.buffer[1][p] += right;
+                }
+                self.position = (p + 1) % self.length;
+                out
+            }
+            _ => (left, right),
+        }
+    }
+
+    pub fn as_json(&self) -> Value {
+        let seconds = |samples: usize| samples as f64 / SAMPLE_RATE as f64;
+        json!({
+            "state": self.state,
+            "channel": self.channel,
+            "quantize": self.quantize,
+            "position": seconds(self.position),
+            "length": seconds(self.length),
+            "layers": self.layers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_looper {
+    use super::*;
+
+    fn run(looper: &mut Looper, samples: usize, value: f32) -> (f32, f32) {
+        let mut out = (0.0, 0.0);
+        for _ in 0..samples {
+            out = looper.tick(value, value);
+        }
+        out
+    }
+
+    fn looper() -> Looper {
+        let mut looper = Looper::new();
+        looper.give_memory(LoopMemory::new());
+        looper
+    }
+
+    #[test]
+    fn record_overdub_undo() {
+        let mut looper = looper();
+        looper.action(LooperAction::Record, 0);
+        run(&mut looper, 100, 1.0);
+        looper.action(LooperAction::Record, 0);
+        assert_eq!(looper.state, LooperState::Playing);
+        assert_eq!(looper.length, 100);
+        // Loop plays back on top of the input
+        assert_eq!(run(&mut looper, 100, 0.5), (1.5, 1.5));
+        looper.action(LooperAction::Overdub, 0);
+        run(&mut looper, 100, 0.5);
+        looper.action(LooperAction::Overdub, 0);
+        assert_eq!(run(&mut looper, 100, 0.0), (1.5, 1.5));
+        looper.action(LooperAction::Undo, 0);
+        assert_eq!(run(&mut looper, 100, 0.0), (1.0, 1.0));
+        looper.action(LooperAction::Undo, 0);
+        assert_eq!(run(&mut looper, 100, 0.0), (1.5, 1.5));
+        looper.action(LooperAction::Stop, 0);
+        assert_eq!(run(&mut looper, 10, 0.25), (0.25, 0.25));
+        looper.action(LooperAction::Clear, 0);
+        assert_eq!(looper.state, LooperState::Empty);
+    }
+    #[test]
+    fn quantized() {
+        let mut looper = looper();
+        looper.quantize = true;
+        looper.action(LooperAction::Record, 0);
+        run(&mut looper, 130, 1.0);
+        looper.action(LooperAction::Record, 100);
+        assert_eq!(looper.length, 100);
+        looper.action(LooperAction::Clear, 0);
+        looper.action(LooperAction::Record, 0);
+        run(&mut looper, 20, 1.0);
+        looper.action(LooperAction::Record, 100);
+        // Never shorter than a bar, the rest is silent
+        assert_eq!(looper.length, 100);
+        assert_eq!(looper.buffer[0][50], 0.0);
+        assert_eq!(LooperAction::from_index(5), Some(LooperAction::Clear));
+        assert_eq!(LooperAction::from_index(6), None);
+    }
+    #[test]
+    fn records_once_it_has_memory() {
+        let mut looper = Looper::new();
+        assert!(looper.needs_memory());
+        looper.action(LooperAction::Record, 0);
+        assert_eq!(looper.state, LooperState::Empty);
+        assert_eq!(run(&mut looper, 10, 1.0), (1.0, 1.0));
+        looper.give_memory(LoopMemory::new());
+        looper.action(LooperAction::Record, 0);
+        assert_eq!(looper.state, LooperState::Recording);
+    }
+}
@@ -0,0 +1,301 @@
+//! Loudness metering of the master bus per ITU-R BS.1770 / EBU R128
+//!
+//! The meter K-weights the stereo master output and reports the momentary (400ms),
+//! short-term (3s) and gated integrated loudness in LUFS, plus the true-peak level
+//! found by 4x oversampling.
+//!
+//! The integrated value uses a histogram of gating block loudness so memory use stays
+//! fixed no matter how long the meter runs.
+
+use serde_json::{json, Value};
+
+use crate::alsa_thread::{CHANNELS, SAMPLE_RATE};
+
+/// Value reported when there is no (or too little) signal to measure
+pub const LOUDNESS_FLOOR: f64 = -70.0;
+
+const BLOCK_SAMPLES: usize = SAMPLE_RATE as usize / 10; // 100ms
+const MOMENTARY_BLOCKS: usize = 4;
+const SHORT_TERM_BLOCKS: usize = 30;
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+// Histogram of gating blocks from the absolute gate up to +10 LUFS in 0.1 LU steps
+const HIST_STEP: f64 = 0.1;
+const HIST_BINS: usize = 800;
+
+const OVERSAMPLE: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+/// Direct form 1 biquad
+#[derive(Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+        Biquad { b, a, x: [0.0; 2], y: [0.0; 2] }
+    }
+    fn process(&mut self, input: f64) -> f64 {
+        let out = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [input, self.x[0]];
+        self.y = [out, self.y[0]];
+        out
+    }
+}
+
+/// The two stage K-weighting filter (coefficients are for 48kHz)
+#[derive(Clone, Copy)]
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    fn new() -> KWeighting {
+        KWeighting {
+            shelf: Biquad::new(
+                [1.53512485958697, -2.69169618940638, 1.19839281085285],
+                [-1.69065929318241, 0.73248077421585],
+            ),
+            high_pass: Biquad::new([1.0, -2.0, 1.0], [-1.99004745483398, 0.99007225036621]),
+        }
+    }
+    fn process(&mut self, input: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(input))
+    }
+}
+
+/// 4x polyphase interpolator used to find inter-sample peaks
+struct TruePeak {
+    phases: [[f64; TAPS_PER_PHASE]; OVERSAMPLE],
+    history: [[f64; TAPS_PER_PHASE]; CHANNELS],
+    pos: usize,
+    peak: f64,
+}
+
+impl TruePeak {
+    fn new() -> TruePeak {
+        // Hann windowed sinc lowpass at the original nyquist
+        let len = OVERSAMPLE * TAPS_PER_PHASE;
+        let center = (len - 1) as f64 / 2.0;
+        let mut phases = [[0.0; TAPS_PER_PHASE]; OVERSAMPLE];
+        for n in 0..len {
+            let t = (n as f64 - center) / OVERSAMPLE as f64;
+            let sinc = if t == 0.0 { 1.0 } else { (std::f64::consts::PI * t).sin() / (std::f64::consts::PI * t) };
+            let window = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * (n as f64 + 0.5) / len as f64).cos();
+            phases[n % OVERSAMPLE][n / OVERSAMPLE] = sinc * window;
+        }
+        TruePeak {
+            phases,
+            history: [[0.0; TAPS_PER_PHASE]; CHANNELS],
+            pos: 0,
+            peak: 0.0,
+        }
+    }
+    fn reset(&mut self) {
+        self.history = [[0.0; TAPS_PER_PHASE]; CHANNELS];
+        self.pos = 0;
+        self.peak = 0.0;
+    }
+    fn add_samples(&mut self, samples: [f64; CHANNELS]) {
+        self.pos = (self.pos + 1) % TAPS_PER_PHASE;
+        for (ch, v) in samples.iter().enumerate() {
+            self.history[ch][self.pos] = *v;
+            for phase in self.phases.iter() {
+                let mut acc = 0.0;
+                for (k, coef) in phase.iter().enumerate() {
+                    acc += coef * self.history[ch][(self.pos + TAPS_PER_PHASE - k) % TAPS_PER_PHASE];
+                }
+                self.peak = self.peak.max(acc.abs());
+            }
+        }
+    }
+}
+
+fn energy_to_lufs(energy: f64) -> f64 {
+    if energy <= 0.0 {
+        return LOUDNESS_FLOOR;
+    }
+    (-0.691 + 10.0 * energy.log10()).max(LOUDNESS_FLOOR)
+}
+
+pub struct LoudnessMeter {
+    filters: [KWeighting; CHANNELS],
+    true_peak: TruePeak,
+    // energy accumulated in the current 100ms block
+    block_sum: f64,
+    block_count: usize,
+    // mean square energy of the last few 100ms blocks
+    blocks: [f64; SHORT_TERM_BLOCKS],
+    block_pos: usize,
+    blocks_seen: usize,
+    // integrated gating histogram
+    hist_counts: Vec<u64>,
+    hist_energy: Vec<f64>,
+}
+
+impl LoudnessMeter {
+    pub fn new() -> LoudnessMeter {
+        LoudnessMeter {
+            filters: [KWeighting::new(); CHANNELS],
+            true_peak: TruePeak::new(),
+            block_sum: 0.0,
+            block_count: 0,
+            blocks: [0.0; SHORT_TERM_BLOCKS],
+            block_pos: 0,
+            blocks_seen: 0,
+            hist_counts: vec![0; HIST_BINS],
+            hist_energy: vec![0.0; HIST_BINS],
+        }
+    }
+
+    /// Clear all measurements (start a new integration period).  This runs on the
+    /// audio thread so the histogram is cleared in place.
+    pub fn reset(&mut self) {
+        self.filters = [KWeighting::new(); CHANNELS];
+        self.true_peak.reset();
+        self.block_sum = 0.0;
+        self.block_count = 0;
+        self.blocks = [0.0; SHORT_TERM_BLOCKS];
+        self.block_pos = 0;
+        self.blocks_seen = 0;
+        self.hist_counts.fill(0);
+        self.hist_energy.fill(0.0);
+    }
+
+    /// Feed a frame of the left/right master output
+    pub fn add_frame(&mut self, left: &[f32], right: &[f32]) {
+        for (l, r) in left.iter().zip(right.iter()) {
+            let samples = [*l as f64, *r as f64];
+            self.true_peak.add_samples(samples);
+            for (ch, v) in samples.iter().enumerate() {
+                let k = self.filters[ch].process(*v);
+                self.block_sum += k * k;
+            }
+            self.block_count += 1;
+            if self.block_count == BLOCK_SAMPLES {
+                self.end_block();
+            }
+        }
+    }
+
+    fn end_block(&mut self) {
+        self.blocks[self.block_pos] = self.block_sum / BLOCK_SAMPLES as f64;
+        self.block_pos = (self.block_pos + 1) % SHORT_TERM_BLOCKS;
+        self.blocks_seen += 1;
+        self.block_sum = 0.0;
+        self.block_count = 0;
+        // Each 100ms step completes a 400ms gating block (75% overlap)
+        if self.blocks_seen >= MOMENTARY_BLOCKS {
+            let energy = self.window_energy(MOMENTARY_BLOCKS);
+            let lufs = energy_to_lufs(energy);
+            if lufs > ABSOLUTE_GATE {
+                let bin = (((lufs - ABSOLUTE_GATE) / HIST_STEP) as usize).min(HIST_BINS - 1);
+                self.hist_counts[bin] += 1;
+                self.hist_energy[bin] += energy;
+            }
+        }
+    }
+
+    fn window_energy(&self, num_blocks: usize) -> f64 {
+        let num_blocks = num_blocks.min(self.blocks_seen);
+        if num_blocks == 0 {
+            return 0.0;
+        }
+        let mut sum = 0.0;
+        for i in 0..num_blocks {
+            sum += self.blocks[(self.block_pos + SHORT_TERM_BLOCKS - 1 - i) % SHORT_TERM_BLOCKS];
+        }
+        sum / num_blocks as f64
+    }
+
+    pub fn momentary(&self) -> f64 {
+        energy_to_lufs(self.window_energy(MOMENTARY_BLOCKS))
+    }
+
+    pub fn short_term(&self) -> f64 {
+        energy_to_lufs(self.window_energy(SHORT_TERM_BLOCKS))
+    }
+
+    pub fn integrated(&self) -> f64 {
+        let gated_mean = |from_bin: usize| {
+            let count: u64 = self.hist_counts[from_bin..].iter().sum();
+            if count == 0 {
+                return 0.0;
+            }
+            self.hist_energy[from_bin..].iter().sum::<f64>() / count as f64
+        };
+        let ungated = energy_to_lufs(gated_mean(0));
+        let threshold = ungated + RELATIVE_GATE;
+        let from_bin = if threshold > ABSOLUTE_GATE {
+            (((threshold - ABSOLUTE_GATE) / HIST_STEP) as usize).min(HIST_BINS - 1)
+        } else {
+            0
+        };
+        energy_to_lufs(gated_mean(from_bin))
+    }
+
+    /// Maximum true-peak level since the last reset in dBTP
+    pub fn true_peak(&self) -> f64 {
+        if self.true_peak.peak <= 0.0 {
+            return LOUDNESS_FLOOR;
+        }
+        (20.0 * self.true_peak.peak.log10()).max(LOUDNESS_FLOOR)
+    }
+
+    pub fn as_json(&self) -> Value {
+        json!({
+            "momentary": self.momentary(),
+            "shortTerm": self.short_term(),
+            "integrated": self.integrated(),
+            "truePeak": self.true_peak(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_loudness {
+    use super::*;
+
+    fn sine(meter: &mut LoudnessMeter, amplitude: f32, freq: f32, seconds: usize) {
+        let mut frame = [0.0; 128];
+        let mut n: usize = 0;
+        while n < SAMPLE_RATE as usize * seconds {
+            for v in frame.iter_mut() {
+                *v = amplitude * (2.0 * std::f32::consts::PI * freq * n as f32 / SAMPLE_RATE as f32).sin();
+                n += 1;
+            }
+            meter.add_frame(&frame, &frame);
+        }
+    }
+
+    #[test]
+    fn silence_is_floor() {
+        let mut meter = LoudnessMeter::new();
+        meter.add_frame(&[0.0; 128], &[0.0; 128]);
+        assert_eq!(meter.integrated(), LOUDNESS_FLOOR);
+        assert_eq!(meter.true_peak(), LOUDNESS_FLOOR);
+    }
+    #[test]
+    fn reference_tone() {
+        // A 1kHz sine at -18 dBFS in both channels reads about -18 LUFS (within a few tenths)
+        let mut meter = LoudnessMeter::new();
+        let amplitude = 10f32.powf(-18.0 / 20.0);
+        sine(&mut meter, amplitude, 1000.0, 4);
+        assert!((meter.integrated() + 18.0).abs() < 0.5, "integrated {}", meter.integrated());
+        assert!((meter.short_term() + 18.0).abs() < 0.5, "short term {}", meter.short_term());
+        assert!((meter.true_peak() + 18.0).abs() < 0.5, "true peak {}", meter.true_peak());
+        meter.reset();
+        assert_eq!(meter.integrated(), LOUDNESS_FLOOR);
+        assert_eq!(meter.true_peak(), LOUDNESS_FLOOR);
+        // Measures the same again after a reset
+        sine(&mut meter, amplitude, 1000.0, 4);
+        assert!((meter.integrated() + 18.0).abs() < 0.5, "integrated after reset {}", meter.integrated());
+    }
+}
@@ -0,0 +1,195 @@
+//! Click track.
+//!
+//! The click follows the global tempo and time signature: one click per beat (so 6/8
+//! clicks on the eighths) with the downbeat accented.  It can go into the mix or only
+//! out of one side, e.g. to a drummer's headphones.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{alsa_thread::SAMPLE_RATE, tempo::Tempo};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ClickSound {
+    Beep,
+    Woodblock,
+    /// Short noise burst, like a hi-hat
+    Tick,
+}
+
+/// Where the click goes
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ClickRoute {
+    /// Both outputs, along with everything else
+    Mix,
+    /// Only the left (A) output
+    Left,
+    /// Only the right (B) output
+    Right,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MetronomeSettings {
+    pub enabled: bool,
+    pub sound: ClickSound,
+    /// Level in dB
+    pub level: f32,
+    pub route: ClickRoute,
+}
+
+impl Default for MetronomeSettings {
+    fn default() -> MetronomeSettings {
+        MetronomeSettings {
+            enabled: false,
+            sound: ClickSound::Beep,
+            level: -6.0,
+            route: ClickRoute::Mix,
+        }
+    }
+}
+
+pub struct Metronome {
+    settings: MetronomeSettings,
+    /// How far through the current beat we are (0..1)
+    phase: f64,
+    /// Beat in the bar, 0 is the downbeat
+    beat: u32,
+    // The click being played
+    envelope: f32,
+    decay: f32,
+    accent: bool,
+    osc_phase: f32,
+    osc_step: f32,
+    noise: u32,
+}
+
+impl Metronome {
+    pub fn new() -> Metronome {
+        Metronome {
+            settings: MetronomeSettings::default(),
+            phase: 0.0,
+            beat: 0,
+            envelope: 0.0,
+            decay: 0.0,
+            accent: false,
+            osc_phase: 0.0,
+            osc_step: 0.0,
+            noise: 1,
+        }
+    }
+
+    pub fn settings(&self) -> MetronomeSettings {
+        self.settings
+    }
+
+    pub fn set(&mut self, settings: MetronomeSettings) {
+        let starting = settings.enabled && !self.settings.enabled;
+        self.settings = settings;
+        // Turning it on starts the count from the downbeat
+        if starting {
+            self.phase = 0.0;
+            self.beat = 0;
+            self.trigger(true);
+        }
+    }
+
+    /// Start a click
+    fn trigger(&mut self, accent: bool) {
+        // (pitch, accented pitch, decay time in seconds)
+        let (pitch, accent_pitch, decay) = match self.settings.sound {
+            ClickSound::Beep => (880.0, 1760.0, 0.05),
+            ClickSound::Woodblock => (1000.0, 1400.0, 0.015),
+            ClickSound::Tick => (0.0, 0.0, 0.008),
+        };
+        let pitch = if accent { accent_pitch } else { pitch };
+        self.osc_step = pitch / SAMPLE_RATE as f32;
+        self.osc_phase = 0.0;
+        self.decay = (-1.0 / (decay * SAMPLE_RATE as f32)).exp();
+        self.envelope = 1.0;
+        self.accent = accent;
+    }
+
+    /// Next sample of the click
+    fn sample(&mut self) -> f32 {
+        if self.envelope < 1e-4 {
+            return 0.0;
+        }
+        let wave = match self.settings.sound {
+            ClickSound::Tick => {
+                // xorshift noise
+                self.noise ^= self.noise << 13;
+                self.noise ^= self.noise >> 17;
+                self.noise ^= self.noise << 5;
+                self.noise as f32 / u32::MAX as f32 * 2.0 - 1.0
+            }
+            _ => {
+                self.osc_phase = (self.osc_phase + self.osc_step).fract();
+                (self.osc_phase * std::f32::consts::TAU).sin()
+            }
+        };
+        let out = wave * self.envelope * if self.accent { 1.0 } else { 0.6 };
+        self.envelope *= self.decay;
+        out
+    }
+
+    /// Add a frame of click to the outputs
+    pub fn mix_into(&mut self, tempo: &Tempo, out_a: &mut [f32], out_b: &mut [f32]) {
+        if !self.settings.enabled {
+            return;
+        }
+        let gain = 10f32.powf(self.settings.level / 20.0);
+        // A beat is 1/unit of a whole note
+        let step = tempo.bpm / 60.0 * tempo.time_signature.unit as f64 / 4.0 / SAMPLE_RATE as f64;
+        for (a, b) in out_a.iter_mut().zip(out_b.iter_mut()) {
+            self.phase += step;
+            if self.phase >= 1.0 {
+                self.phase -= 1.0;
+                self.beat = (self.beat + 1) % tempo.time_signature.beats.max(1);
+                self.trigger(self.beat == 0);
+            }
+            let click = gain * self.sample();
+            match self.settings.route {
+                ClickRoute::Mix => {
+                    *a += click;
+                    *b += click;
+                }
+                ClickRoute::Left => *a += click,
+                ClickRoute::Right => *b += click,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_metronome {
+    use super::*;
+    use crate::tempo::{SharedTempo, TimeSignature};
+
+    #[test]
+    fn accents_the_downbeat() {
+        let mut tempo = Tempo::new(SharedTempo::new(120.0));
+        tempo.time_signature = TimeSignature { beats: 3, unit: 4 };
+        let mut metronome = Metronome::new();
+        metronome.set(MetronomeSettings { enabled: true, route: ClickRoute::Right, ..Default::default() });
+        assert!(metronome.accent);
+        let mut accents = 0;
+        let mut clicks = 0;
+        let mut right = false;
+        // A bit over two seconds at 120 is 4 beats after the first one
+        for _ in 0..800 {
+            let (mut out_a, mut out_b) = ([0.0; 128], [0.0; 128]);
+            let beat = metronome.beat;
+            metronome.mix_into(&tempo, &mut out_a, &mut out_b);
+            if metronome.beat != beat {
+                clicks += 1;
+                accents += metronome.accent as usize;
+            }
+            assert_eq!(out_a, [0.0; 128]);
+            right |= out_b.iter().any(|s| *s != 0.0);
+        }
+        assert_eq!((clicks, accents), (4, 1));
+        assert!(right);
+    }
+}
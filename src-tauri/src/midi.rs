@@ -0,0 +1,367 @@
+//! MIDI input from an ALSA rawmidi port (footswitches, expression pedals, ...).
+//!
+//! A thread reads the port, runs the bytes through a [`MidiParser`](MidiParser) and
+//! hands each message to a callback.  Events are shaped like the u/x `MidiEvent` so
+//! they can be forwarded as they are.
+
+use std::{
+    ffi::CString,
+    io::{Read, Write},
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use alsa::{card, poll::{poll, Descriptors}, rawmidi::{self, Rawmidi}, Ctl, Direction};
+use log::{error, info};
+use serde::{Serialize, Serializer};
+
+use crate::{error::FxError, tempo::{SharedTempo, CLOCKS_PER_BEAT}};
+
+/// How long a poll waits before checking if the thread should stop (ms)
+const POLL_TIMEOUT: i32 = 100;
+
+pub const CLOCK: u8 = 0xF8;
+pub const START: u8 = 0xFA;
+pub const CONTINUE: u8 = 0xFB;
+pub const STOP: u8 = 0xFC;
+
+/// Same numbering as `MidiMessageType` in the u/x
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MidiMessageType {
+    NoteOff = 0,
+    NoteOn,
+    PolyPressure,
+    ControlChange,
+    ProgramChange,
+    ChannelPressure,
+    PitchBend,
+    SystemMessage,
+    UnknownType,
+}
+
+impl Serialize for MidiMessageType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(*self as u8)
+    }
+}
+
+/// A decoded MIDI message.  For channel messages `note` and `velocity` are the two data
+/// bytes (controller and value for a CC, program and 0 for a program change).  System
+/// messages carry the status byte in `note`.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct MidiEvent {
+    #[serde(rename = "type")]
+    pub msg_type: MidiMessageType,
+    pub channel: u8,
+    pub note: u8,
+    pub velocity: u8,
+}
+
+impl MidiEvent {
+    /// A system message with the given status byte (clock, start, stop, ...)
+    pub fn is_system(&self, status: u8) -> bool {
+        self.msg_type == MidiMessageType::SystemMessage && self.note == status
+    }
+
+    fn system(status: u8) -> MidiEvent {
+        MidiEvent {
+            msg_type: MidiMessageType::SystemMessage,
+            channel: 0,
+            note: status,
+            velocity: 0,
+        }
+    }
+}
+
+/// Turns a byte stream into messages.  Handles running status, real-time bytes in the
+/// middle of a message and skips over sysex.
+pub struct MidiParser {
+    status: u8,
+    data: [u8; 2],
+    count: usize,
+    in_sysex: bool,
+}
+
+impl MidiParser {
+    pub fn new() -> MidiParser {
+        MidiParser {
+            status: 0,
+            data: [0; 2],
+            count: 0,
+            in_sysex: false,
+        }
+    }
+
+    fn data_len(status: u8) -> usize {
+        match status & 0xF0 {
+            0xC0 | 0xD0 => 1,
+            _ => 2,
+        }
+    }
+
+    /// Feed one byte, getting back a message when one is complete
+    pub fn parse(&mut self, byte: u8) -> Option<MidiEvent> {
+        if byte >= 0xF8 {
+            // Real-time (clock, start, stop, ...) can show up anywhere
+            return Some(MidiEvent::system(byte));
+        }
+        if byte >= 0x80 {
+            self.count = 0;
+            self.in_sysex = byte == 0xF0;
+            if byte >= 0xF0 {
+                // System common cancels running status
+                self.status = 0;
+                return match byte {
+                    0xF0 | 0xF7 => None,
+                    _ => Some(MidiEvent::system(byte)),
+                };
+            }
+            self.status = byte;
+            return None;
+        }
+        if self.in_sysex || self.status == 0 {
+            return None;
+        }
+        self.data[self.count] = byte;
+        self.count += 1;
+        if self.count < MidiParser::data_len(self.status) {
+            return None;
+        }
+        // Keep the status for the next message (running status)
+        self.count = 0;
+        let msg_type = match self.status & 0xF0 {
+            0x80 => MidiMessageType::NoteOff,
+            0x90 if self.data[1] == 0 => MidiMessageType::NoteOff,
+            0x90 => MidiMessageType::NoteOn,
+            0xA0 => MidiMessageType::PolyPressure,
+            0xB0 => MidiMessageType::ControlChange,
+            0xC0 => MidiMessageType::ProgramChange,
+            0xD0 => MidiMessageType::ChannelPressure,
+            0xE0 => MidiMessageType::PitchBend,
+            _ => MidiMessageType::UnknownType,
+        };
+        let velocity = if MidiParser::data_len(self.status) == 2 { self.data[1] } else { 0 };
+        Some(MidiEvent {
+            msg_type,
+            channel: self.status & 0x0F,
+            note: self.data[0],
+            velocity,
+        })
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MidiPort {
+    /// ALSA name to open, like `hw:1,0,0`
+    pub id: String,
+    pub name: String,
+    /// Input (true) or output port
+    pub input: bool,
+}
+
+/// Every rawmidi port on every card
+pub fn list_ports() -> Result<Vec<MidiPort>, FxError> {
+    let mut ports = Vec::new();
+    for card in card::Iter::new() {
+        let card = card?;
+        let ctl = Ctl::from_card(&card, false)?;
+        for info in rawmidi::Iter::new(&ctl) {
+            let info = info?;
+            ports.push(MidiPort {
+                id: format!("hw:{},{},{}", card.get_index(), info.get_device(), info.get_subdevice()),
+                name: info.get_subdevice_name()?,
+                input: info.get_stream() == Direction::Capture,
+            });
+        }
+    }
+    Ok(ports)
+}
+
+/// The MIDI input thread
+pub struct MidiInput {
+    running: Option<Arc<AtomicBool>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MidiInput {
+    pub fn new() -> MidiInput {
+        MidiInput {
+            running: None,
+            handle: None,
+        }
+    }
+
+    /// Open a port and send everything that arrives on it to `on_event`
+    pub fn start<F>(&mut self, port: &str, mut on_event: F) -> Result<(), FxError>
+    where
+        F: FnMut(MidiEvent) + Send + 'static,
+    {
+        // A reader that ended on an error still needs reaping
+        if self.running.as_ref().is_some_and(|running| !running.load(Ordering::Relaxed)) {
+            self.stop()?;
+        }
+        if self.running.is_some() {
+            return Err(FxError::MidiPortBusy(String::from("midi input is already open")));
+        }
+        let name = CString::new(port).map_err(|e| FxError::DeviceOpen(e.to_string()))?;
+        let midi = Rawmidi::open(&name, Direction::Capture, false)
+            .map_err(|e| FxError::DeviceOpen(format!("midi port {}: {}", port, e)))?;
+        info!("starting midi input: {}", port);
+        let running = Arc::new(AtomicBool::new(true));
+        self.running = Some(running.clone());
+        let handle = thread::Builder::new().name("MIDI Input".to_string()).spawn(move || {
+            let mut parser = MidiParser::new();
+            let mut buf = [0u8; 256];
+            while running.load(Ordering::Relaxed) {
+                let ready = midi.get().and_then(|mut fds| poll(&mut fds, POLL_TIMEOUT));
+                match ready {
+                    Ok(0) => continue,
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!("midi poll failed: {}", e);
+                        break;
+                    }
+                }
+                match midi.io().read(&mut buf) {
+                    Ok(n) => {
+                        for byte in &buf[..n] {
+                            if let Some(event) = parser.parse(*byte) {
+                                on_event(event);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        error!("midi read failed: {}", e);
+                        break;
+                    }
+                }
+            }
+            // Lets start know the port can be opened again
+            running.store(false, Ordering::Relaxed);
+            info!("midi input ended");
+        })?;
+        self.handle = Some(handle);
+        Ok(())
+    }
+
+    pub fn stop(&mut self) -> Result<(), FxError> {
+        match self.running.take() {
+            Some(running) => running.store(false, Ordering::Relaxed),
+            None => return Err(FxError::MidiPortClosed(String::from("midi input is not open"))),
+        }
+        if let Some(handle) = self.handle.take() {
+            handle.join().map_err(|_| FxError::Io(String::from("midi thread panicked")))?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends MIDI clock at the current tempo so other gear can follow us
+pub struct MidiClockOut {
+    running: Option<Arc<AtomicBool>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MidiClockOut {
+    pub fn new() -> MidiClockOut {
+        MidiClockOut {
+            running: None,
+            handle: None,
+        }
+    }
+
+    pub fn start(&mut self, port: &str, tempo: SharedTempo) -> Result<(), FxError> {
+        // A clock that stopped on a write error can be started again
+        if self.running.as_ref().is_some_and(|running| !running.load(Ordering::Relaxed)) {
+            self.stop()?;
+        }
+        if self.running.is_some() {
+            return Err(FxError::MidiPortBusy(String::from("midi clock out is already open")));
+        }
+        let name = CString::new(port).map_err(|e| FxError::DeviceOpen(e.to_string()))?;
+        let midi = Rawmidi::open(&name, Direction::Playback, false)
+            .map_err(|e| FxError::DeviceOpen(format!("midi port {}: {}", port, e)))?;
+        info!("starting midi clock out: {}", port);
+        let running = Arc::new(AtomicBool::new(true));
+        self.running = Some(running.clone());
+        let handle = thread::Builder::new().name("MIDI Clock".to_string()).spawn(move || {
+            let mut out = midi.io();
+            let mut sent = out.write_all(&[START]);
+            // Schedule from the last pulse so timing errors don't add up
+            let mut next = Instant::now();
+            while sent.is_ok() && running.load(Ordering::Relaxed) {
+                sent = out.write_all(&[CLOCK]);
+                next += Duration::from_secs_f64(60.0 / (tempo.get() * CLOCKS_PER_BEAT as f64));
+                thread::sleep(next.saturating_duration_since(Instant::now()));
+            }
+            // A port that stops taking bytes (unplugged) ends the clock instead of
+            // failing on every pulse
+            match sent.and_then(|()| out.write_all(&[STOP])) {
+                Ok(()) => info!("midi clock out ended"),
+                Err(e) => error!("midi clock write failed, clock out stopped: {}", e),
+            }
+            running.store(false, Ordering::Relaxed);
+        })?;
+        self.handle = Some(handle);
+        Ok(())
+    }
+
+    pub fn stop(&mut self) -> Result<(), FxError> {
+        match self.running.take() {
+            Some(running) => running.store(false, Ordering::Relaxed),
+            None => return Err(FxError::MidiPortClosed(String::from("midi clock out is not open"))),
+        }
+        if let Some(handle) = self.handle.take() {
+            handle.join().map_err(|_| FxError::Io(String::from("midi clock thread panicked")))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_midi {
+    use super::*;
+
+    fn parse_all(bytes: &[u8]) -> Vec<MidiEvent> {
+        let mut parser = MidiParser::new();
+        bytes.iter().filter_map(|b| parser.parse(*b)).collect()
+    }
+    fn event(msg_type: MidiMessageType, channel: u8, note: u8, velocity: u8) -> MidiEvent {
+        MidiEvent { msg_type, channel, note, velocity }
+    }
+
+    #[test]
+    fn running_status() {
+        let events = parse_all(&[0xB1, 7, 100, 7, 90, 0x90, 60, 64, 60, 0]);
+        assert_eq!(
+            events,
+            vec![
+                event(MidiMessageType::ControlChange, 1, 7, 100),
+                event(MidiMessageType::ControlChange, 1, 7, 90),
+                event(MidiMessageType::NoteOn, 0, 60, 64),
+                event(MidiMessageType::NoteOff, 0, 60, 0),
+            ]
+        );
+    }
+    #[test]
+    fn program_change_and_realtime() {
+        // A clock tick in the middle of a CC does not break it
+        let events = parse_all(&[0xC2, 5, 0xB0, 64, 0xF8, 127]);
+        assert_eq!(
+            events,
+            vec![
+                event(MidiMessageType::ProgramChange, 2, 5, 0),
+                MidiEvent::system(0xF8),
+                event(MidiMessageType::ControlChange, 0, 64, 127),
+            ]
+        );
+    }
+    #[test]
+    fn skips_sysex() {
+        let events = parse_all(&[0xF0, 0x7E, 1, 2, 0xF7, 3, 0xC0, 1]);
+        assert_eq!(events, vec![event(MidiMessageType::ProgramChange, 0, 1, 0)]);
+        assert_eq!(serde_json::to_value(events[0]).unwrap(), serde_json::json!({ "type": 4, "channel": 0, "note": 1, "velocity": 0 }));
+    }
+}
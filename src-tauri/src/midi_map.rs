@@ -0,0 +1,216 @@
+//! MIDI learn: controller (CC) messages bound to pedal settings.
+//!
+//! While learn is armed with a target setting, the next CC that arrives is bound to
+//! it using the setting's own min/max.  After that every value of that CC is scaled
+//! through the mapping's curve and applied with a `SetEffectConfig`, the same as an
+//! edit from the u/x.  The table is kept in `midi_map.json`.
+
+use std::path::PathBuf;
+
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{error::FxError, midi::{MidiEvent, MidiMessageType}, param_message::JamCommand, utils::{read_json, write_json}};
+
+/// How a CC value (0..127) is spread over min..max
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Curve {
+    #[default]
+    Linear,
+    /// Fine control at the bottom of the pedal travel
+    Exponential,
+    /// Fine control at the top of the pedal travel
+    Logarithmic,
+}
+
+impl Curve {
+    /// Shape a 0..1 position
+    pub fn apply(&self, x: f64) -> f64 {
+        match self {
+            Curve::Linear => x,
+            Curve::Exponential => (10f64.powf(x) - 1.0) / 9.0,
+            Curve::Logarithmic => (1.0 + 9.0 * x).log10(),
+        }
+    }
+}
+
+/// The setting a CC drives
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CcTarget {
+    pub channel: usize,
+    pub pedal: usize,
+    pub setting: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CcMapping {
+    pub midi_channel: u8,
+    pub cc: u8,
+    #[serde(flatten)]
+    pub target: CcTarget,
+    pub min: f64,
+    pub max: f64,
+    #[serde(default)]
+    pub curve: Curve,
+    /// On/off settings (like bypass) switch at the halfway point
+    #[serde(default)]
+    pub switch: bool,
+}
+
+impl CcMapping {
+    /// Bind a CC to a target using the range of the setting on the live board.
+    /// `pedal_info` is the `pedalInfo` array from the board config.
+    pub fn learn(target: CcTarget, event: &MidiEvent, pedal_info: &Value) -> Result<CcMapping, FxError> {
+        let setting = pedal_info[target.channel]["effects"][target.pedal]["settings"]
+            .as_array()
+            .and_then(|settings| settings.iter().find(|s| s["name"] == target.setting.as_str()))
+            .ok_or_else(|| {
+                FxError::UnknownSetting(format!(
+                    "channel {} pedal {} has no setting {}",
+                    target.channel, target.pedal, target.setting
+                ))
+            })?;
+        Ok(CcMapping {
+            midi_channel: event.channel,
+            cc: event.note,
+            target,
+            min: setting["min"].as_f64().unwrap_or(0.0),
+            max: setting["max"].as_f64().unwrap_or(1.0),
+            curve: Curve::Linear,
+            switch: setting["value"].is_boolean(),
+        })
+    }
+
+    /// Setting value for a CC value
+    pub fn value(&self, cc_value: u8) -> Value {
+        if self.switch {
+            return json!(cc_value >= 64);
+        }
+        let x = self.curve.apply(f64::from(cc_value) / 127.0);
+        json!(self.min + (self.max - self.min) * x)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MidiMap {
+    #[serde(default)]
+    pub cc: Vec<CcMapping>,
+}
+
+impl MidiMap {
+    /// Commands for an incoming message (one CC can drive several settings)
+    pub fn commands(&self, event: &MidiEvent) -> Vec<JamCommand> {
+        if event.msg_type != MidiMessageType::ControlChange {
+            return Vec::new();
+        }
+        self.cc
+            .iter()
+            .filter(|m| m.midi_channel == event.channel && m.cc == event.note)
+            .map(|m| JamCommand::SetEffectConfig {
+                channel: m.target.channel,
+                pedal: m.target.pedal,
+                setting: json!({ "name": m.target.setting, "value": m.value(event.velocity) }),
+            })
+            .collect()
+    }
+
+    /// Add a learned mapping, replacing any old binding of the same CC to the same target
+    pub fn bind(&mut self, mapping: CcMapping) {
+        self.cc.retain(|m| !(m.midi_channel == mapping.midi_channel && m.cc == mapping.cc && m.target == mapping.target));
+        self.cc.push(mapping);
+    }
+}
+
+/// The map on disk plus the learn state
+pub struct MidiMapping {
+    path: PathBuf,
+    pub map: MidiMap,
+    /// Set while waiting for a CC to learn
+    pub learn: Option<CcTarget>,
+}
+
+impl MidiMapping {
+    /// A map file that can't be read starts empty rather than stopping the app
+    pub fn new(path: PathBuf) -> MidiMapping {
+        let map = match read_json(&path) {
+            Ok(map) => map,
+            Err(e) if path.exists() => {
+                error!("midi map {} can't be read, starting empty: {}", path.display(), e);
+                MidiMap::default()
+            }
+            Err(_) => MidiMap::default(),
+        };
+        MidiMapping { path, map, learn: None }
+    }
+
+    pub fn save(&mut self, map: MidiMap) -> Result<(), FxError> {
+        write_json(&self.path, &map)?;
+        self.map = map;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_midi_map {
+    use super::*;
+    use crate::utils::test_utils::TempDir;
+
+    fn cc(channel: u8, cc: u8, value: u8) -> MidiEvent {
+        MidiEvent { msg_type: MidiMessageType::ControlChange, channel, note: cc, velocity: value }
+    }
+    fn pedal_info() -> Value {
+        json!([
+            { "boardId": 0, "effects": [{ "name": "Tremelo", "settings": [
+                { "name": "bypass", "value": false },
+                { "name": "rate", "min": 0.01, "max": 8.0, "value": 4.0 },
+            ] }] },
+            { "boardId": 1, "effects": [] },
+        ])
+    }
+    fn target(setting: &str) -> CcTarget {
+        CcTarget { channel: 0, pedal: 0, setting: String::from(setting) }
+    }
+
+    #[test]
+    fn learn_and_apply() {
+        let mut map = MidiMap::default();
+        map.bind(CcMapping::learn(target("rate"), &cc(0, 11, 30), &pedal_info()).unwrap());
+        map.bind(CcMapping::learn(target("bypass"), &cc(0, 64, 0), &pedal_info()).unwrap());
+        assert_eq!(map.cc[0].max, 8.0);
+        assert!(map.cc[1].switch);
+        assert_eq!(
+            map.commands(&cc(0, 11, 127)),
+            vec![JamCommand::SetEffectConfig { channel: 0, pedal: 0, setting: json!({ "name": "rate", "value": 8.0 }) }]
+        );
+        assert_eq!(map.commands(&cc(0, 64, 127))[0], JamCommand::SetEffectConfig { channel: 0, pedal: 0, setting: json!({ "name": "bypass", "value": true }) });
+        // Other midi channel
+        assert!(map.commands(&cc(1, 11, 127)).is_empty());
+        // Learning again replaces the binding
+        map.bind(CcMapping::learn(target("rate"), &cc(0, 11, 0), &pedal_info()).unwrap());
+        assert_eq!(map.cc.len(), 2);
+        assert!(CcMapping::learn(target("depth"), &cc(0, 1, 0), &pedal_info()).is_err());
+    }
+    #[test]
+    fn curves() {
+        for curve in [Curve::Linear, Curve::Exponential, Curve::Logarithmic] {
+            assert!(curve.apply(0.0).abs() < 1e-9);
+            assert!((curve.apply(1.0) - 1.0).abs() < 1e-9);
+        }
+        assert!(Curve::Exponential.apply(0.5) < 0.5);
+        assert!(Curve::Logarithmic.apply(0.5) > 0.5);
+    }
+
+    #[test]
+    fn unreadable_map_starts_empty() {
+        let dir = TempDir::new("midi-map");
+        let path = dir.path().join("midi_map.json");
+        std::fs::write(&path, "{ \"cc\": [").unwrap();
+        let mapping = MidiMapping::new(path);
+        assert_eq!(mapping.map, MidiMap::default());
+    }
+}
@@ -0,0 +1,184 @@
+//! Output mixer state: how the boards are combined into the master output.
+//!
+//! Besides its own path into the master each board can send to the aux buses.  A bus is
+//! a shared effects chain (one reverb for both boards rather than a reverb on each) whose
+//! return is summed into the master like another channel.
+
+use serde::{Deserialize, Serialize};
+
+use crate::alsa_thread::CHANNELS;
+
+/// Number of aux buses
+pub const BUSES: usize = 2;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelMix {
+    /// Level in dB
+    pub level: f32,
+    pub mute: bool,
+    /// -1 (left) to 1 (right), only used in stereo routing.  Balance for a stereo board.
+    #[serde(default)]
+    pub pan: f32,
+}
+
+impl Default for ChannelMix {
+    fn default() -> ChannelMix {
+        ChannelMix { level: 0.0, mute: false, pan: 0.0 }
+    }
+}
+
+/// A board's send to an aux bus.  Sends are taken after the channel fader.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AuxSend {
+    /// Level in dB
+    pub level: f32,
+    pub on: bool,
+}
+
+/// How the boards get to the two outputs
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum OutputRouting {
+    /// Both boards summed to both outputs
+    #[default]
+    Mono,
+    /// Board 0 to the left output, board 1 to the right (e.g. guitar amp and PA)
+    Split,
+    /// Both boards panned into a stereo mix
+    Stereo,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MixerState {
+    pub channels: [ChannelMix; CHANNELS],
+    #[serde(default)]
+    pub routing: OutputRouting,
+    /// Send from each board to each bus
+    #[serde(default)]
+    pub sends: [[AuxSend; BUSES]; CHANNELS],
+    /// Bus returns into the master, pan is the bus balance
+    #[serde(default)]
+    pub returns: [ChannelMix; BUSES],
+}
+
+fn fader_gain(mix: &ChannelMix) -> f32 {
+    if mix.mute {
+        return 0.0;
+    }
+    10f32.powf(mix.level / 20.0)
+}
+
+/// Linear gain from each side of a source (columns) into the left and right outputs
+/// (rows).  Split routing needs the board; a bus return goes to both outputs.
+fn matrix(mix: &ChannelMix, routing: OutputRouting, channel: Option<usize>, stereo: bool) -> [[f32; 2]; 2] {
+    let gain = fader_gain(mix);
+    let pan = mix.pan.clamp(-1.0, 1.0);
+    match (routing, channel) {
+        (OutputRouting::Split, Some(0)) => [[gain / 2.0; 2], [0.0; 2]],
+        (OutputRouting::Split, Some(_)) => [[0.0; 2], [gain / 2.0; 2]],
+        (OutputRouting::Stereo, _) if stereo => {
+            // Balance: turning one side down leaves the other where it was
+            [[gain * (1.0 - pan).min(1.0), 0.0], [0.0, gain * (1.0 + pan).min(1.0)]]
+        }
+        (OutputRouting::Stereo, _) => {
+            // Constant power pan
+            let angle = (pan + 1.0) * std::f32::consts::FRAC_PI_4;
+            [[gain * angle.cos() / 2.0; 2], [gain * angle.sin() / 2.0; 2]]
+        }
+        // A mono board has the same signal on both sides so this is just the gain
+        _ => [[gain / 2.0; 2]; 2],
+    }
+}
+
+impl MixerState {
+    /// Linear gain for a board going into the master
+    pub fn gain(&self, channel: usize) -> f32 {
+        fader_gain(&self.channels[channel])
+    }
+
+    /// Linear gain from each side of a board (columns) into the left and right outputs (rows).
+    /// In stereo routing the pan is a balance for a stereo board.
+    pub fn output_matrix(&self, channel: usize, stereo: bool) -> [[f32; 2]; 2] {
+        matrix(&self.channels[channel], self.routing, Some(channel), stereo)
+    }
+
+    /// Same as `output_matrix` for a bus return, which is always stereo
+    pub fn return_matrix(&self, bus: usize) -> [[f32; 2]; 2] {
+        matrix(&self.returns[bus], self.routing, None, true)
+    }
+
+    /// Linear gain from a board into a bus
+    pub fn send_gain(&self, channel: usize, bus: usize) -> f32 {
+        let send = &self.sends[channel][bus];
+        if !send.on {
+            return 0.0;
+        }
+        self.gain(channel) * 10f32.powf(send.level / 20.0)
+    }
+}
+
+#[cfg(test)]
+mod test_mixer {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn gain() {
+        let mut mixer = MixerState::default();
+        assert_eq!(mixer.gain(0), 1.0);
+        mixer.channels[0].level = -6.0;
+        assert!((mixer.gain(0) - 0.501).abs() < 1e-3);
+        mixer.channels[0].mute = true;
+        assert_eq!(mixer.gain(0), 0.0);
+        assert_eq!(mixer.gain(1), 1.0);
+    }
+
+    #[test]
+    fn routing() {
+        // Scenes saved before routing existed are mono
+        let mut mixer: MixerState = serde_json::from_value(json!({
+            "channels": [{ "level": 0.0, "mute": false }, { "level": 0.0, "mute": true }]
+        }))
+        .unwrap();
+        assert_eq!(mixer.routing, OutputRouting::Mono);
+        assert_eq!(mixer.output_matrix(0, false), [[0.5, 0.5], [0.5, 0.5]]);
+        assert_eq!(mixer.output_matrix(1, false), [[0.0, 0.0], [0.0, 0.0]]);
+        mixer.channels[1].mute = false;
+        mixer.routing = OutputRouting::Split;
+        assert_eq!(mixer.output_matrix(1, false), [[0.0, 0.0], [0.5, 0.5]]);
+        mixer.routing = OutputRouting::Stereo;
+        mixer.channels[0].pan = -1.0;
+        // A mono board has the same signal on both sides
+        let [left, right] = mixer.output_matrix(0, false).map(|row| row[0] + row[1]);
+        assert!((left - 1.0).abs() < 1e-6 && right.abs() < 1e-6);
+        let [left, right] = mixer.output_matrix(1, false).map(|row| row[0] + row[1]);
+        assert!((left - right).abs() < 1e-6);
+    }
+
+    #[test]
+    fn balance() {
+        let mut mixer = MixerState { routing: OutputRouting::Stereo, ..Default::default() };
+        mixer.channels[0].pan = -0.5;
+        assert_eq!(mixer.output_matrix(0, true), [[1.0, 0.0], [0.0, 0.5]]);
+        assert_eq!(mixer.output_matrix(1, true), [[1.0, 0.0], [0.0, 1.0]]);
+        mixer.returns[1].pan = 1.0;
+        assert_eq!(mixer.return_matrix(1), [[0.0, 0.0], [0.0, 1.0]]);
+    }
+
+    #[test]
+    fn sends() {
+        let mut mixer = MixerState::default();
+        assert_eq!(mixer.send_gain(0, 1), 0.0);
+        mixer.sends[0][1] = AuxSend { level: -20.0, on: true };
+        mixer.channels[0].level = 20.0;
+        assert!((mixer.send_gain(0, 1) - 1.0).abs() < 1e-6);
+        // Split routing doesn't pick a side for a bus
+        mixer.routing = OutputRouting::Split;
+        assert_eq!(mixer.return_matrix(0), [[0.5, 0.5], [0.5, 0.5]]);
+        mixer.channels[0].mute = true;
+        assert_eq!(mixer.send_gain(0, 1), 0.0);
+    }
+}
@@ -1,12 +1,13 @@
 //! Structure used to pass messages to the audio engine from the websocket
 
 use num::{FromPrimitive, ToPrimitive};
-use serde_json::json;
-use simple_error::bail;
-use std::fmt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{fmt, str::FromStr, sync::mpsc::Sender};
 
-use crate::box_error::BoxError;
+use crate::{alsa_thread::CHANNELS, error::FxError, looper::LooperAction, metronome::MetronomeSettings, mixer::{MixerState, OutputRouting, BUSES}, scope::ScopeTap, tempo::{TempoLock, TempoSource}};
 
+/// Legacy RTJam parameter numbers
 #[derive(FromPrimitive, ToPrimitive)]
 pub enum JamParam {
     GetConfigJson = 27,
@@ -15,21 +16,23 @@ pub enum JamParam {
     DeletePedal,
     MovePedal,
     LoadBoard,
+    // 33 is TuneChannel on the u/x side
+    GetScope = 34,
+    ResetLoudness,
+    LooperControl,
     ShutdownAudio = 9999,
 }
 
-/// The ParamMessage is used to define the API to the sound engine from the outside
-/// world.  Interpretation of this message is by the
-/// [`JamEngine`](crate::sound::jam_engine::JamEngine).
+/// The ParamMessage is the legacy (RTJam) encoding of a command to the sound engine.
+/// It is still accepted on the wire but gets converted into a [`JamCommand`](JamCommand)
+/// before it is sent to the [`BoardSet`](crate::board_set::BoardSet).
 ///
 /// A ParamMessage consist of a param value [`JamParam`](JamParam), and 4
 /// other values.  interpretation of the values is dependent on the nature of the command.
 ///
 /// other values are ivalue_1: integer, ivalue_2: integer, fvalue: float, and svalue: string.
 ///
-/// ### TODO
-/// This encoding needs to get normalized.  But it will require coordination between the u/x and the
-/// sound unit.
+/// New code should use the typed [`JamCommand`](JamCommand) instead.
 pub struct ParamMessage {
     pub param: JamParam,
     pub ivalue_1: i64,
@@ -57,13 +60,13 @@ impl ParamMessage {
           "sValue": self.svalue,
         })
     }
-    pub fn _from_string(data: &str) -> Result<ParamMessage, BoxError> {
+    pub fn _from_string(data: &str) -> Result<ParamMessage, FxError> {
         let raw = serde_json::from_str(data)?;
         Self::from_json(&raw)
     }
-    pub fn from_json(raw: &serde_json::Value) -> Result<ParamMessage, BoxError> {
+    pub fn from_json(raw: &serde_json::Value) -> Result<ParamMessage, FxError> {
         if !(raw["param"].is_i64() || raw["param"].is_string()) {
-            bail!("no param in message");
+            return Err(FxError::ProtocolParse(String::from("no param in message")));
         }
         let mut param: Option<JamParam> = None;
         if raw["param"].is_i64() {
@@ -102,12 +105,174 @@ impl ParamMessage {
                 Ok(msg)
             }
             None => {
-                bail!("can't extract param");
+                Err(FxError::ProtocolParse(format!("unknown param: {}", raw["param"])))
             }
         }
     }
 }
 
+/// Protocol v2: the typed commands understood by the sound engine.
+///
+/// On the wire these are JSON objects tagged with a `cmd` field, e.g.
+/// `{ "cmd": "movePedal", "channel": 0, "from": 2, "to": 0 }`.  Messages without a `cmd`
+/// are treated as a legacy [`ParamMessage`](ParamMessage) and converted.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "cmd", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum JamCommand {
+    GetConfigJson,
+    /// setting is a `{ "name": ..., "value": ... }` object
+    SetEffectConfig { channel: usize, pedal: usize, setting: Value },
+    InsertPedal { channel: usize, index: usize, pedal_type: String },
+    DeletePedal { channel: usize, index: usize },
+    MovePedal { channel: usize, from: usize, to: usize },
+    /// Edits to the pedals on one branch of the Parallel Split at `index`
+    InsertBranchPedal { channel: usize, index: usize, branch: usize, position: usize, pedal_type: String },
+    DeleteBranchPedal { channel: usize, index: usize, branch: usize, position: usize },
+    MoveBranchPedal { channel: usize, index: usize, branch: usize, from: usize, to: usize },
+    /// setting is a `{ "name": ..., "value": ... }` object
+    SetBranchConfig { channel: usize, index: usize, branch: usize, position: usize, setting: Value },
+    /// board is the config array of pedals (see `PedalBoard::load_from_json`)
+    LoadBoard { channel: usize, board: Value },
+    /// Load both channels at the same frame.  A null board leaves that channel alone.
+    LoadBoards { boards: [Value; CHANNELS] },
+    GetScope { channel: usize, tap: ScopeTap, milliseconds: usize, #[serde(default)] points: usize },
+    ResetLoudness,
+    /// level is in dB.  pan (-1 to 1) is left alone when it is missing.
+    SetChannelMix { channel: usize, level: f32, mute: bool, #[serde(default)] pan: Option<f32> },
+    /// Mono sum, board 0 left / board 1 right, or stereo
+    SetOutputRouting { routing: OutputRouting },
+    /// level is in dB
+    SetSend { channel: usize, bus: usize, level: f32, on: bool },
+    /// level is in dB.  pan (the bus balance) is left alone when it is missing.
+    SetBusReturn { bus: usize, level: f32, mute: bool, #[serde(default)] pan: Option<f32> },
+    /// board is the config array of pedals for the bus's effects chain
+    LoadBus { bus: usize, board: Value },
+    /// Reply with both board configs, the bus chains and the mixer (a `sceneEvent`)
+    CaptureScene,
+    /// Swap in both boards, the bus chains and the mixer in one go.  A null bus is left as it is.
+    RecallScene { boards: [Value; CHANNELS], mixer: MixerState, #[serde(default)] buses: [Value; BUSES] },
+    SetTempo { bpm: f64, #[serde(default)] source: TempoSource },
+    /// Replies with a `tempoEvent` from the second tap on
+    TapTempo,
+    SetTimeSignature { beats: u32, unit: u32 },
+    /// Lock the time based pedals on a board to the tempo
+    SetTempoLock { channel: usize, lock: TempoLock },
+    /// channel None loops the master bus.  The channel is picked up when recording starts.
+    Looper { action: LooperAction, #[serde(default)] channel: Option<usize> },
+    /// Round the loop length to whole bars when recording stops
+    LooperQuantize { enabled: bool },
+    /// Click track on/off, sound, level and routing
+    SetMetronome { settings: MetronomeSettings },
+    /// Step back through the edits made to a board
+    Undo { channel: usize },
+    Redo { channel: usize },
+    ShutdownAudio,
+}
+
+impl JamCommand {
+    /// True for commands that change what is saved in the session
+    pub fn is_edit(&self) -> bool {
+        !matches!(
+            self,
+            JamCommand::GetConfigJson
+                | JamCommand::GetScope { .. }
+                | JamCommand::ResetLoudness
+                | JamCommand::CaptureScene
+                | JamCommand::Looper { .. }
+                | JamCommand::LooperQuantize { .. }
+                | JamCommand::SetMetronome { .. }
+                // Comes in every beat while a clock runs and would keep putting off the session save
+                | JamCommand::SetTempo { source: TempoSource::MidiClock, .. }
+                | JamCommand::ShutdownAudio
+        )
+    }
+
+    /// Parse a command from either the v2 tagged format or the legacy RTJam format
+    pub fn from_json(raw: &Value) -> Result<JamCommand, FxError> {
+        if raw.get("cmd").is_some() {
+            return Ok(serde_json::from_value(raw.clone())?);
+        }
+        JamCommand::try_from(ParamMessage::from_json(raw)?)
+    }
+}
+
+/// A command plus the optional id the u/x uses to match up the `ackEvent` reply.
+///
+/// Commands that produce data (config, scope, scene) send it to `reply` when it is set
+/// instead of out the event channel.
+#[derive(Debug)]
+pub struct CommandRequest {
+    pub request_id: Option<u64>,
+    pub command: JamCommand,
+    pub reply: Option<Sender<Value>>,
+}
+
+impl CommandRequest {
+    pub fn new(command: JamCommand) -> CommandRequest {
+        CommandRequest {
+            request_id: None,
+            command,
+            reply: None,
+        }
+    }
+    /// Parse a command (either format) with an optional "requestId" field
+    pub fn from_json(raw: &Value) -> Result<CommandRequest, FxError> {
+        Ok(CommandRequest {
+            request_id: raw["requestId"].as_u64(),
+            command: JamCommand::from_json(raw)?,
+            reply: None,
+        })
+    }
+}
+
+impl TryFrom<ParamMessage> for JamCommand {
+    type Error = FxError;
+
+    fn try_from(msg: ParamMessage) -> Result<JamCommand, FxError> {
+        let channel = msg.ivalue_1 as usize;
+        Ok(match msg.param {
+            JamParam::GetConfigJson => JamCommand::GetConfigJson,
+            JamParam::SetEffectConfig => JamCommand::SetEffectConfig {
+                channel,
+                pedal: msg.ivalue_2 as usize,
+                setting: serde_json::from_str(&msg.svalue)?,
+            },
+            JamParam::InsertPedal => JamCommand::InsertPedal {
+                channel,
+                index: msg.ivalue_2 as usize,
+                pedal_type: msg.svalue,
+            },
+            JamParam::DeletePedal => JamCommand::DeletePedal {
+                channel,
+                index: msg.ivalue_2 as usize,
+            },
+            JamParam::MovePedal => JamCommand::MovePedal {
+                channel,
+                from: msg.ivalue_2 as usize,
+                to: msg.fvalue.round() as usize,
+            },
+            JamParam::LoadBoard => JamCommand::LoadBoard {
+                channel,
+                board: serde_json::from_str(&msg.svalue)?,
+            },
+            JamParam::GetScope => JamCommand::GetScope {
+                channel,
+                tap: ScopeTap::from_str(&msg.svalue)?,
+                milliseconds: msg.ivalue_2 as usize,
+                points: msg.fvalue.round() as usize,
+            },
+            JamParam::ResetLoudness => JamCommand::ResetLoudness,
+            // iValue1 is the action, iValue2 the channel (-1 for the master bus)
+            JamParam::LooperControl => JamCommand::Looper {
+                action: LooperAction::from_index(msg.ivalue_1)
+                    .ok_or_else(|| FxError::ProtocolParse(format!("unknown looper action {}", msg.ivalue_1)))?,
+                channel: usize::try_from(msg.ivalue_2).ok(),
+            },
+            JamParam::ShutdownAudio => JamCommand::ShutdownAudio,
+        })
+    }
+}
+
 impl fmt::Display for ParamMessage {
     // This trait requires `fmt` with this exact signature.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -172,6 +337,45 @@ mod test_param_message {
         let msg = ParamMessage::from_json(&raw).unwrap();
         assert_eq!(msg.fvalue, 2.0);
     }
+    #[test]
+    fn typed_command() {
+        let raw = json!({ "cmd": "movePedal", "channel": 1, "from": 3, "to": 0 });
+        let cmd = JamCommand::from_json(&raw).unwrap();
+        assert_eq!(cmd, JamCommand::MovePedal { channel: 1, from: 3, to: 0 });
+        let raw = json!({ "cmd": "insertPedal", "channel": 0, "index": 2, "pedalType": "Delay" });
+        let cmd = JamCommand::from_json(&raw).unwrap();
+        assert_eq!(cmd, JamCommand::InsertPedal { channel: 0, index: 2, pedal_type: String::from("Delay") });
+        let cmd = JamCommand::from_json(&json!({ "cmd": "setTempo", "bpm": 90.0 })).unwrap();
+        assert_eq!(cmd, JamCommand::SetTempo { bpm: 90.0, source: TempoSource::Manual });
+        assert!(cmd.is_edit());
+        assert!(!JamCommand::SetTempo { bpm: 90.0, source: TempoSource::MidiClock }.is_edit());
+    }
+    #[test]
+    fn legacy_command() {
+        let raw = json!({ "param": 31, "iValue1": 1, "iValue2": 3, "fValue": 0.0 });
+        let cmd = JamCommand::from_json(&raw).unwrap();
+        assert_eq!(cmd, JamCommand::MovePedal { channel: 1, from: 3, to: 0 });
+        let raw = json!({ "param": 28, "iValue1": 0, "iValue2": 2, "sValue": r#"{"name":"level","value":0.5}"# });
+        let cmd = JamCommand::from_json(&raw).unwrap();
+        assert_eq!(cmd, JamCommand::SetEffectConfig { channel: 0, pedal: 2, setting: json!({ "name": "level", "value": 0.5 }) });
+        let raw = json!({ "param": 36, "iValue1": 0, "iValue2": -1, "fValue": 0.0 });
+        let cmd = JamCommand::from_json(&raw).unwrap();
+        assert_eq!(cmd, JamCommand::Looper { action: LooperAction::Record, channel: None });
+    }
+    #[test]
+    fn request_id() {
+        let raw = json!({ "requestId": 42, "cmd": "deletePedal", "channel": 0, "index": 1 });
+        let req = CommandRequest::from_json(&raw).unwrap();
+        assert_eq!(req.request_id, Some(42));
+        assert_eq!(req.command, JamCommand::DeletePedal { channel: 0, index: 1 });
+        let raw = json!({ "param": 27 });
+        assert_eq!(CommandRequest::from_json(&raw).unwrap().request_id, None);
+    }
+    #[test]
+    fn bad_command() {
+        assert!(JamCommand::from_json(&json!({ "cmd": "launchRockets" })).is_err());
+        assert!(JamCommand::from_json(&json!({ "param": 32, "iValue1": 0, "sValue": "not json" })).is_err());
+    }
 }
 
 // TODO:  convert this into rust for the param
@@ -0,0 +1,206 @@
+//! Versioning, validation and migration of saved boards.
+//!
+//! Presets on disk and in export files carry a `schemaVersion`.  Anything older than
+//! [`SCHEMA_VERSION`](SCHEMA_VERSION) is run through the migrations in order before it
+//! is validated and handed to `PedalBoard::load_from_json`.  Files without a version
+//! (the original `defaultBoards.json` / localStorage shape) are version 0.
+//!
+//! Export files are either a single board or a bank of boards:
+//!
+//! ```json
+//! { "schemaVersion": 1, "kind": "board", "preset": { "id": 1, "name": "...", "config": [...] } }
+//! { "schemaVersion": 1, "kind": "bank", "presets": [ ... ] }
+//! ```
+
+use serde_json::{json, Map, Value};
+
+use crate::{error::FxError, presets::Preset};
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A migration upgrades a preset object from version N to N + 1.  When pedal-board
+/// renames a setting, bump the schema version and rename it in a new migration so old
+/// presets keep their values.
+type Migration = fn(&mut Map<String, Value>) -> Result<(), FxError>;
+
+/// MIGRATIONS[n] takes version n to version n + 1
+const MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [migrate_v0];
+
+/// v0 -> v1: RTJam stored the config as a JSON string and did not always number the
+/// pedals.
+fn migrate_v0(preset: &mut Map<String, Value>) -> Result<(), FxError> {
+    if let Some(config) = preset.get_mut("config") {
+        *config = upgrade_config(config.take())?;
+    }
+    Ok(())
+}
+
+/// Bring a bare config (array of pedals) into the shape PedalBoard expects.  This is
+/// safe to run on a config that is already current.
+pub fn upgrade_config(config: Value) -> Result<Value, FxError> {
+    let mut config = match config {
+        Value::String(s) => serde_json::from_str(&s)
+            .map_err(|e| FxError::InvalidPreset(format!("config string is not JSON: {}", e)))?,
+        other => other,
+    };
+    if let Some(pedals) = config.as_array_mut() {
+        for (idx, pedal) in pedals.iter_mut().enumerate() {
+            if let Some(pedal) = pedal.as_object_mut() {
+                pedal.entry("index").or_insert(json!(idx));
+            }
+        }
+    }
+    Ok(config)
+}
+
+/// Check a config has the shape PedalBoard expects
+pub fn validate_config(config: &Value) -> Result<(), FxError> {
+    validate_pedals(config, String::from("config"))
+}
+
+fn validate_pedals(config: &Value, path: String) -> Result<(), FxError> {
+    let invalid = |path: String, what: &str| FxError::InvalidPreset(format!("{}: {}", path, what));
+    let pedals = config.as_array().ok_or_else(|| invalid(path.clone(), "must be an array of pedals"))?;
+    for (p, pedal) in pedals.iter().enumerate() {
+        let path = format!("{}[{}]", path, p);
+        if !pedal.is_object() {
+            return Err(invalid(path, "pedal must be an object"));
+        }
+        if !pedal["name"].is_string() {
+            return Err(invalid(path, "pedal has no name"));
+        }
+        let settings = pedal["settings"]
+            .as_array()
+            .ok_or_else(|| invalid(path.clone(), "settings must be an array"))?;
+        for (s, setting) in settings.iter().enumerate() {
+            let path = format!("{}.settings[{}]", path, s);
+            if !setting["name"].is_string() {
+                return Err(invalid(path, "setting has no name"));
+            }
+            if !(setting["value"].is_number() || setting["value"].is_boolean()) {
+                return Err(invalid(path, "setting value must be a number or boolean"));
+            }
+        }
+        // A parallel split carries its own pedals
+        if let Some(branches) = pedal.get("branches") {
+            let branches = branches.as_array().ok_or_else(|| invalid(path.clone(), "branches must be an array"))?;
+            for (b, branch) in branches.iter().enumerate() {
+                validate_pedals(branch, format!("{}.branches[{}]", path, b))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run the migrations on a preset object, then validate and parse it
+pub fn upgrade_preset(raw: Value) -> Result<Preset, FxError> {
+    let mut obj = match raw {
+        Value::Object(obj) => obj,
+        _ => return Err(FxError::InvalidPreset(String::from("preset must be an object"))),
+    };
+    let name = obj.get("name").and_then(|n| n.as_str()).unwrap_or("?").to_string();
+    let version = obj.get("schemaVersion").and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+    if version > SCHEMA_VERSION {
+        return Err(FxError::InvalidPreset(format!(
+            "preset '{}' is schema version {}, this build only understands up to {}",
+            name, version, SCHEMA_VERSION
+        )));
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(&mut obj)?;
+    }
+    obj.insert(String::from("schemaVersion"), json!(SCHEMA_VERSION));
+    let raw = Value::Object(obj);
+    validate_config(&raw["config"]).map_err(|e| FxError::InvalidPreset(format!("preset '{}': {}", name, e.detail())))?;
+    serde_json::from_value(raw).map_err(|e| FxError::InvalidPreset(format!("preset '{}': {}", name, e)))
+}
+
+pub fn export_board(preset: &Preset) -> Value {
+    json!({
+        "schemaVersion": SCHEMA_VERSION,
+        "kind": "board",
+        "preset": preset,
+    })
+}
+
+pub fn export_bank(presets: &[Preset]) -> Value {
+    json!({
+        "schemaVersion": SCHEMA_VERSION,
+        "kind": "bank",
+        "presets": presets,
+    })
+}
+
+/// Read the presets out of an export file.  Also accepts an unversioned array of
+/// boards such as `defaultBoards.json`.
+pub fn import(data: Value) -> Result<Vec<Preset>, FxError> {
+    let (version, raw_presets) = match data {
+        Value::Array(list) => (json!(0), list),
+        Value::Object(mut obj) => {
+            let version = obj.get("schemaVersion").cloned().unwrap_or(json!(0));
+            match obj.get("kind").and_then(|k| k.as_str()) {
+                Some("board") => (version, vec![obj.remove("preset").unwrap_or_default()]),
+                Some("bank") => match obj.remove("presets") {
+                    Some(Value::Array(list)) => (version, list),
+                    _ => return Err(FxError::InvalidPreset(String::from("bank has no presets array"))),
+                },
+                // A single bare preset
+                _ => (version, vec![Value::Object(obj)]),
+            }
+        }
+        _ => return Err(FxError::InvalidPreset(String::from("import must be a board, a bank or an array of boards"))),
+    };
+    let mut presets = Vec::new();
+    for mut raw in raw_presets {
+        // The presets inside an export file inherit the file version
+        if let Some(obj) = raw.as_object_mut() {
+            obj.entry("schemaVersion").or_insert(version.clone());
+            obj.entry("id").or_insert(json!(0));
+        }
+        presets.push(upgrade_preset(raw)?);
+    }
+    Ok(presets)
+}
+
+#[cfg(test)]
+mod test_preset_schema {
+    use super::*;
+
+    fn pedal() -> Value {
+        json!({ "name": "Delay", "settings": [{ "name": "duration", "value": 211.0 }, { "name": "bypass", "value": true }] })
+    }
+
+    #[test]
+    fn migrates_v0_string_config() {
+        let raw = json!({ "id": 3, "name": "old", "config": json!([pedal()]).to_string() });
+        let preset = upgrade_preset(raw).unwrap();
+        assert_eq!(preset.schema_version, SCHEMA_VERSION);
+        assert_eq!(preset.config[0]["index"], 0);
+        assert_eq!(preset.config[0]["settings"][0]["value"], 211.0);
+    }
+    #[test]
+    fn validation_errors_have_a_path() {
+        let raw = json!({ "id": 1, "name": "broken", "config": [pedal(), { "name": "Chorus", "settings": [{ "name": "rate" }] }] });
+        match upgrade_preset(raw) {
+            Err(FxError::InvalidPreset(msg)) => assert!(msg.contains("config[1].settings[0]"), "{}", msg),
+            other => panic!("expected invalid preset, got {:?}", other),
+        }
+        let split = json!({ "name": "Parallel Split", "settings": [], "branches": [[pedal()], [{ "name": "Chorus" }]] });
+        match validate_config(&json!([split])) {
+            Err(FxError::InvalidPreset(msg)) => assert!(msg.contains("config[0].branches[1][0]"), "{}", msg),
+            other => panic!("expected invalid preset, got {:?}", other),
+        }
+        let raw = json!({ "id": 1, "name": "future", "schemaVersion": SCHEMA_VERSION + 1, "config": [] });
+        assert!(upgrade_preset(raw).is_err());
+    }
+    #[test]
+    fn import_export_round_trip() {
+        let preset = upgrade_preset(json!({ "id": 7, "name": "a", "config": [pedal()] })).unwrap();
+        let bank = export_bank(&[preset.clone(), preset.clone()]);
+        assert_eq!(import(bank).unwrap(), vec![preset.clone(), preset.clone()]);
+        assert_eq!(import(export_board(&preset)).unwrap(), vec![preset.clone()]);
+        // defaultBoards.json style
+        let legacy = json!([{ "id": 243, "name": "Champ", "description": null, "config": [pedal()] }]);
+        assert_eq!(import(legacy).unwrap()[0].name, "Champ");
+    }
+}
@@ -0,0 +1,192 @@
+//! Library of saved boards kept on disk by the backend.
+//!
+//! Each preset is a JSON file named `<id>.json` in the presets directory, kept in a
+//! [`JsonStore`](crate::store::JsonStore).  The preset has the same shape as the entries
+//! in the u/x `defaultBoards.json` where `config` is the array of pedals that
+//! `PedalBoard::load_from_json` understands.  Old files are upgraded by
+//! [`preset_schema`](crate::preset_schema) as they are read.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{error::FxError, preset_schema::{self, SCHEMA_VERSION}, store::{JsonStore, Stored}};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Preset {
+    #[serde(default)]
+    pub schema_version: u32,
+    pub id: u64,
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub program: Option<i64>,
+    pub config: Value,
+}
+
+/// What the u/x sends to save a board.  Without an id a new preset is created.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PresetInput {
+    #[serde(default)]
+    pub id: Option<u64>,
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub program: Option<i64>,
+    pub config: Value,
+}
+
+/// Short form used for listing the library
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PresetSummary {
+    pub id: u64,
+    pub name: String,
+    pub description: Option<String>,
+    pub program: Option<i64>,
+}
+
+impl Stored for Preset {
+    const KIND: &'static str = "preset";
+    fn id(&self) -> u64 {
+        self.id
+    }
+    fn set_id(&mut self, id: u64) {
+        self.id = id;
+    }
+    fn from_stored(raw: Value) -> Result<Preset, FxError> {
+        preset_schema::upgrade_preset(raw)
+    }
+}
+
+pub struct PresetStore {
+    store: JsonStore<Preset>,
+}
+
+impl PresetStore {
+    pub fn new(dir: PathBuf) -> Result<PresetStore, FxError> {
+        Ok(PresetStore { store: JsonStore::new(dir)? })
+    }
+
+    pub fn list(&self) -> Result<Vec<PresetSummary>, FxError> {
+        let list = self.store.list()?.into_iter().map(|preset| PresetSummary {
+            id: preset.id,
+            name: preset.name,
+            description: preset.description,
+            program: preset.program,
+        });
+        Ok(list.collect())
+    }
+
+    pub fn get(&self, id: u64) -> Result<Preset, FxError> {
+        self.store.get(id)
+    }
+
+    pub fn save(&self, input: PresetInput) -> Result<Preset, FxError> {
+        let config = preset_schema::upgrade_config(input.config)?;
+        preset_schema::validate_config(&config)?;
+        self.store.save(Preset {
+            schema_version: SCHEMA_VERSION,
+            id: input.id.unwrap_or(0),
+            name: input.name,
+            description: input.description,
+            program: input.program,
+            config,
+        })
+    }
+
+    pub fn rename(&self, id: u64, name: &str) -> Result<Preset, FxError> {
+        let mut preset = self.get(id)?;
+        preset.name = String::from(name);
+        self.store.save(preset)
+    }
+
+    pub fn delete(&self, id: u64) -> Result<(), FxError> {
+        self.store.delete(id)
+    }
+
+    /// Add imported presets to the library under new ids
+    pub fn import(&self, presets: Vec<Preset>) -> Result<Vec<Preset>, FxError> {
+        let mut saved = Vec::new();
+        for preset in presets {
+            saved.push(self.save(PresetInput {
+                id: None,
+                name: preset.name,
+                description: preset.description,
+                program: preset.program,
+                config: preset.config,
+            })?);
+        }
+        Ok(saved)
+    }
+
+    /// Copy a preset under a new id (and name)
+    pub fn duplicate(&self, id: u64, name: Option<String>) -> Result<Preset, FxError> {
+        let mut preset = self.get(id)?;
+        preset.id = 0;
+        preset.name = name.unwrap_or(format!("{} copy", preset.name));
+        self.store.save(preset)
+    }
+}
+
+#[cfg(test)]
+mod test_presets {
+    use super::*;
+    use crate::utils::test_utils::TempDir;
+    use serde_json::json;
+
+    // The directory goes away with the TempDir so keep it alongside the store
+    fn store() -> (TempDir, PresetStore) {
+        let dir = TempDir::new("presets");
+        let store = PresetStore::new(dir.path().to_path_buf()).unwrap();
+        (dir, store)
+    }
+    fn input(name: &str) -> PresetInput {
+        PresetInput {
+            id: None,
+            name: String::from(name),
+            description: None,
+            program: None,
+            config: json!([{ "index": 0, "name": "Delay", "settings": [{ "name": "bypass", "value": false }] }]),
+        }
+    }
+
+    #[test]
+    fn save_list_get() {
+        let (_dir, store) = store();
+        let a = store.save(input("clean")).unwrap();
+        let b = store.save(input("dirty")).unwrap();
+        assert_eq!((a.id, b.id), (1, 2));
+        let names: Vec<String> = store.list().unwrap().into_iter().map(|p| p.name).collect();
+        assert_eq!(names, vec!["clean", "dirty"]);
+        assert_eq!(store.get(2).unwrap(), b);
+        assert!(store.save(PresetInput { config: json!("nope"), ..input("bad") }).is_err());
+    }
+    #[test]
+    fn rename_duplicate_delete() {
+        let (_dir, store) = store();
+        let a = store.save(input("clean")).unwrap();
+        assert_eq!(store.rename(a.id, "sparkle").unwrap().name, "sparkle");
+        let copy = store.duplicate(a.id, None).unwrap();
+        assert_eq!(copy.name, "sparkle copy");
+        assert_eq!(copy.config, a.config);
+        store.delete(a.id).unwrap();
+        assert!(matches!(store.get(a.id), Err(FxError::NotFound(_))));
+        assert_eq!(store.list().unwrap().len(), 1);
+    }
+    #[test]
+    fn broken_file_is_left_out() {
+        let (dir, store) = store();
+        store.save(input("clean")).unwrap();
+        std::fs::write(dir.path().join("2.json"), "{ \"id\": 2, \"name\": ").unwrap();
+        std::fs::write(dir.path().join("3.json"), "{ \"id\": 3, \"name\": \"no config\" }").unwrap();
+        let names: Vec<String> = store.list().unwrap().into_iter().map(|p| p.name).collect();
+        assert_eq!(names, vec!["clean"]);
+        assert!(store.get(2).is_err());
+    }
+}
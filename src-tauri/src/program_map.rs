@@ -0,0 +1,100 @@
+//! MIDI Program Change to preset/scene recall.
+//!
+//! The table lives next to the presets in `program_map.json` and maps a program
+//! number on a MIDI channel to either a preset (loaded onto one board) or a scene.
+
+use std::path::PathBuf;
+
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::FxError, midi::{MidiEvent, MidiMessageType}, utils::{read_json, write_json}};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum ProgramTarget {
+    /// Load a preset onto a channel
+    Preset { id: u64, channel: usize },
+    /// Recall a scene (both boards and the mixer)
+    Scene { id: u64 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProgramMapping {
+    pub midi_channel: u8,
+    pub program: u8,
+    #[serde(flatten)]
+    pub target: ProgramTarget,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProgramMap {
+    #[serde(default)]
+    pub programs: Vec<ProgramMapping>,
+}
+
+impl ProgramMap {
+    /// What a Program Change message should recall, if anything
+    pub fn lookup(&self, event: &MidiEvent) -> Option<&ProgramTarget> {
+        if event.msg_type != MidiMessageType::ProgramChange {
+            return None;
+        }
+        self.programs
+            .iter()
+            .find(|m| m.midi_channel == event.channel && m.program == event.note)
+            .map(|m| &m.target)
+    }
+}
+
+/// The table on disk
+pub struct ProgramMapStore {
+    path: PathBuf,
+    pub map: ProgramMap,
+}
+
+impl ProgramMapStore {
+    /// A table file that can't be read starts empty rather than stopping the app
+    pub fn new(path: PathBuf) -> ProgramMapStore {
+        let map = match read_json(&path) {
+            Ok(map) => map,
+            Err(e) if path.exists() => {
+                error!("program map {} can't be read, starting empty: {}", path.display(), e);
+                ProgramMap::default()
+            }
+            Err(_) => ProgramMap::default(),
+        };
+        ProgramMapStore { path, map }
+    }
+
+    pub fn save(&mut self, map: ProgramMap) -> Result<(), FxError> {
+        write_json(&self.path, &map)?;
+        self.map = map;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_program_map {
+    use super::*;
+    use serde_json::json;
+
+    fn pc(channel: u8, program: u8) -> MidiEvent {
+        MidiEvent { msg_type: MidiMessageType::ProgramChange, channel, note: program, velocity: 0 }
+    }
+
+    #[test]
+    fn lookup() {
+        let map: ProgramMap = serde_json::from_value(json!({ "programs": [
+            { "midiChannel": 0, "program": 1, "kind": "preset", "id": 4, "channel": 1 },
+            { "midiChannel": 0, "program": 2, "kind": "scene", "id": 9 },
+        ] }))
+        .unwrap();
+        assert_eq!(map.lookup(&pc(0, 1)), Some(&ProgramTarget::Preset { id: 4, channel: 1 }));
+        assert_eq!(map.lookup(&pc(0, 2)), Some(&ProgramTarget::Scene { id: 9 }));
+        assert_eq!(map.lookup(&pc(1, 2)), None);
+        let cc = MidiEvent { msg_type: MidiMessageType::ControlChange, ..pc(0, 1) };
+        assert_eq!(map.lookup(&cc), None);
+    }
+}
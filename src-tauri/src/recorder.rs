@@ -0,0 +1,325 @@
+//! Recording takes to disk.
+//!
+//! While a take is running the audio thread pushes every frame of the two inputs (dry)
+//! and both sides of the two board outputs (wet) into a lock-free ring buffer.  A writer thread drains
+//! the ring into one mono WAV file per stem, so the dry stems can be re-amped through a
+//! board later.  Frames that don't fit in the ring are dropped and counted as overruns.
+//!
+//! Stems are written as 32 bit float WAV; there is no FLAC encoder available to us.
+
+use std::{
+    ffi::CString,
+    fs::{self, File},
+    io::BufWriter,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+use log::{debug, error, info};
+use rtrb::{Consumer, Producer, RingBuffer};
+use serde_json::{json, Value};
+use tauri::ipc::Channel;
+
+use crate::{alsa_thread::{FRAME_SIZE, SAMPLE_RATE}, error::FxError};
+
+/// Dry (input) and wet (board output, left and right) for each channel
+pub const STEMS: usize = 6;
+const STEM_NAMES: [&str; STEMS] = ["dry-0", "dry-1", "wet-0-l", "wet-0-r", "wet-1-l", "wet-1-r"];
+/// How much audio the ring holds while the disk catches up (seconds)
+const RING_SECONDS: usize = 4;
+/// A take stops before the disk gets this full (bytes)
+const MIN_FREE_SPACE: u64 = 200 * 1024 * 1024;
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+const DRAIN_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Flags shared by the audio thread and the writer
+struct Shared {
+    armed: AtomicBool,
+    /// Frames dropped because the ring was full
+    overruns: AtomicU64,
+}
+
+/// The audio thread end of the recorder
+pub struct RecordTap {
+    producer: Producer<f32>,
+    shared: Arc<Shared>,
+}
+
+impl RecordTap {
+    /// Push one frame of every stem.  Does nothing unless a take is running.
+    pub fn add_frame(&mut self, stems: [&[f32]; STEMS]) {
+        if !self.shared.armed.load(Ordering::Acquire) {
+            return;
+        }
+        let len = stems[0].len();
+        match self.producer.write_chunk_uninit(len * STEMS) {
+            Ok(chunk) => {
+                // Interleaved, one sample of each stem at a time
+                chunk.fill_from_iter((0..len).flat_map(|i| stems.map(|stem| stem[i])));
+            }
+            Err(_) => {
+                self.shared.overruns.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// Ring buffer that holds a whole number of frames so a frame never straddles the wrap
+fn ring(frames: usize) -> (RecordTap, Consumer<f32>, Arc<Shared>) {
+    let (producer, consumer) = RingBuffer::new(frames * FRAME_SIZE * STEMS);
+    let shared = Arc::new(Shared {
+        armed: AtomicBool::new(false),
+        overruns: AtomicU64::new(0),
+    });
+    (RecordTap { producer, shared: shared.clone() }, consumer, shared)
+}
+
+/// The files of one take
+struct Take {
+    name: String,
+    dir: PathBuf,
+    files: Vec<PathBuf>,
+    writers: Vec<WavWriter<BufWriter<File>>>,
+    samples: u64,
+}
+
+impl Take {
+    /// Open a file per stem, named `<time>_<name>_<stem>.wav`
+    fn create(dir: &Path, name: &str) -> Result<Take, FxError> {
+        let name = file_name(name);
+        let stamp = chrono::Local::now().format("%Y-%m-%d_%H-%M-%S");
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        let mut files = Vec::new();
+        let mut writers = Vec::new();
+        for stem in STEM_NAMES {
+            let path = dir.join(format!("{}_{}_{}.wav", stamp, name, stem));
+            writers.push(WavWriter::create(&path, spec)?);
+            files.push(path);
+        }
+        Ok(Take { name, dir: dir.to_path_buf(), files, writers, samples: 0 })
+    }
+
+    /// Write whatever is in the ring
+    fn drain(&mut self, consumer: &mut Consumer<f32>) -> Result<(), FxError> {
+        let available = consumer.slots();
+        if available == 0 {
+            return Ok(());
+        }
+        let chunk = consumer
+            .read_chunk(available)
+            .map_err(|e| FxError::Io(e.to_string()))?;
+        let (first, second) = chunk.as_slices();
+        for frame in first.chunks_exact(STEMS).chain(second.chunks_exact(STEMS)) {
+            for (writer, sample) in self.writers.iter_mut().zip(frame) {
+                writer.write_sample(*sample)?;
+            }
+        }
+        self.samples += (available / STEMS) as u64;
+        chunk.commit_all();
+        Ok(())
+    }
+
+    /// Fix up the WAV headers
+    fn finalize(self) -> Result<(), FxError> {
+        for writer in self.writers {
+            writer.finalize()?;
+        }
+        Ok(())
+    }
+
+    fn as_json(&self, recording: bool, overruns: u64) -> Value {
+        json!({
+            "recording": recording,
+            "name": self.name,
+            "files": self.files,
+            "seconds": self.samples as f64 / SAMPLE_RATE as f64,
+            "overruns": overruns,
+        })
+    }
+}
+
+/// Keep names from the u/x (preset, song) safe to use in a file name
+fn file_name(name: &str) -> String {
+    let name: String = name
+        .trim()
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '-' })
+        .collect();
+    if name.is_empty() {
+        String::from("take")
+    } else {
+        name
+    }
+}
+
+/// Bytes available to us on the file system holding `dir`
+fn free_space(dir: &Path) -> Result<u64, FxError> {
+    let path = CString::new(dir.as_os_str().as_bytes()).map_err(|e| FxError::Io(e.to_string()))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Runs takes.  The matching [`RecordTap`](RecordTap) lives on the audio thread.
+pub struct Recorder {
+    consumer: Option<Consumer<f32>>,
+    shared: Arc<Shared>,
+    event_channel: Channel<Value>,
+    running: Option<Arc<AtomicBool>>,
+    handle: Option<JoinHandle<(Consumer<f32>, Value)>>,
+}
+
+impl Recorder {
+    pub fn new(event_channel: Channel<Value>) -> (Recorder, RecordTap) {
+        let (tap, consumer, shared) = ring(RING_SECONDS * SAMPLE_RATE as usize / FRAME_SIZE);
+        let recorder = Recorder {
+            consumer: Some(consumer),
+            shared,
+            event_channel,
+            running: None,
+            handle: None,
+        };
+        (recorder, tap)
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.running.is_some()
+    }
+
+    /// Start a take in `dir`.  `name` (usually the preset or song) goes in the file names.
+    pub fn start(&mut self, dir: &Path, name: &str) -> Result<Value, FxError> {
+        // A take that stopped itself (disk full) still needs reaping
+        if self.handle.as_ref().is_some_and(|handle| handle.is_finished()) {
+            self.stop()?;
+        }
+        if self.running.is_some() {
+            return Err(FxError::AlreadyRecording);
+        }
+        fs::create_dir_all(dir)?;
+        let free = free_space(dir)?;
+        if free < MIN_FREE_SPACE {
+            return Err(FxError::RecordDiskFull { free_mb: free / 1_000_000 });
+        }
+        let mut take = Take::create(dir, name)?;
+        let mut consumer = self.consumer.take().ok_or(FxError::AlreadyRecording)?;
+        // Throw away anything left over from the last take
+        if let Ok(stale) = consumer.read_chunk(consumer.slots()) {
+            stale.commit_all();
+        }
+        info!("recording take: {:?}", take.files);
+        let started = json!({ "recordingEvent": take.as_json(true, 0) });
+        self.shared.overruns.store(0, Ordering::Relaxed);
+        self.shared.armed.store(true, Ordering::Release);
+        let running = Arc::new(AtomicBool::new(true));
+        self.running = Some(running.clone());
+        let shared = self.shared.clone();
+        let event_channel = self.event_channel.clone();
+        let handle = thread::Builder::new().name("Recorder".to_string()).spawn(move || {
+            let result = write_take(&mut take, &mut consumer, &running, &shared, &event_channel);
+            shared.armed.store(false, Ordering::Release);
+            // Pick up a frame pushed while we were disarming
+            thread::sleep(DRAIN_INTERVAL);
+            let result = result.and_then(|()| take.drain(&mut consumer));
+            let mut event = take.as_json(false, shared.overruns.load(Ordering::Relaxed));
+            if let Err(e) = result.and(take.finalize()) {
+                error!("recording failed: {}", e);
+                event["error"] = json!(e);
+            }
+            info!("take ended: {}", event);
+            let event = json!({ "recordingEvent": event });
+            if let Err(e) = event_channel.send(event.clone()) {
+                debug!("recording event not sent: {}", e);
+            }
+            (consumer, event)
+        })?;
+        self.handle = Some(handle);
+        Ok(started)
+    }
+
+    /// Finish the take, returning its final `recordingEvent`
+    pub fn stop(&mut self) -> Result<Value, FxError> {
+        match self.running.take() {
+            Some(running) => running.store(false, Ordering::Relaxed),
+            None => return Err(FxError::NotRecording),
+        }
+        let handle = self.handle.take().ok_or(FxError::NotRecording)?;
+        let (consumer, event) = handle
+            .join()
+            .map_err(|_| FxError::Io(String::from("recorder thread panicked")))?;
+        self.consumer = Some(consumer);
+        Ok(event)
+    }
+}
+
+// Drain the ring until told to stop, reporting progress and disk space as we go
+fn write_take(
+    take: &mut Take,
+    consumer: &mut Consumer<f32>,
+    running: &AtomicBool,
+    shared: &Shared,
+    event_channel: &Channel<Value>,
+) -> Result<(), FxError> {
+    let mut last_report = Instant::now();
+    while running.load(Ordering::Relaxed) {
+        take.drain(consumer)?;
+        if last_report.elapsed() >= REPORT_INTERVAL {
+            last_report = Instant::now();
+            let free = free_space(&take.dir)?;
+            let mut event = take.as_json(true, shared.overruns.load(Ordering::Relaxed));
+            event["freeSpace"] = json!(free);
+            if let Err(e) = event_channel.send(json!({ "recordingEvent": event })) {
+                debug!("recording event not sent: {}", e);
+            }
+            if free < MIN_FREE_SPACE {
+                return Err(FxError::RecordDiskFull { free_mb: free / 1_000_000 });
+            }
+        }
+        thread::sleep(DRAIN_INTERVAL);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_recorder {
+    use super::*;
+    use crate::utils::test_utils::TempDir;
+
+    #[test]
+    fn stems_and_overruns() {
+        let dir = TempDir::new("take");
+        let (mut tap, mut consumer, shared) = ring(2);
+        let frames: Vec<Vec<f32>> = (0..STEMS).map(|stem| vec![stem as f32 / 10.0; FRAME_SIZE]).collect();
+        let stems: [&[f32]; STEMS] = std::array::from_fn(|stem| &frames[stem][..]);
+        // Nothing goes in until armed
+        tap.add_frame(stems);
+        assert_eq!(consumer.slots(), 0);
+        shared.armed.store(true, Ordering::Release);
+        for _ in 0..3 {
+            tap.add_frame(stems);
+        }
+        assert_eq!(shared.overruns.load(Ordering::Relaxed), 1);
+
+        let mut take = Take::create(dir.path(), "Clean / Crunch").unwrap();
+        take.drain(&mut consumer).unwrap();
+        assert_eq!(take.samples, 2 * FRAME_SIZE as u64);
+        let files = take.files.clone();
+        take.finalize().unwrap();
+        assert_eq!(files.len(), STEMS);
+        assert!(files[2].to_str().unwrap().ends_with("_Clean---Crunch_wet-0-l.wav"));
+        let mut reader = hound::WavReader::open(&files[2]).unwrap();
+        assert_eq!(reader.len(), 2 * FRAME_SIZE as u32);
+        assert_eq!(reader.samples::<f32>().next().unwrap().unwrap(), 0.2);
+        assert_eq!(file_name(""), "take");
+    }
+}
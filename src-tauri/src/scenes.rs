@@ -0,0 +1,53 @@
+//! Scenes capture the whole rig: the boards on both channels, the aux bus chains and the mixer.  A scene
+//! is recalled with a single command so everything changes at the same frame boundary.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{alsa_thread::CHANNELS, mixer::{MixerState, BUSES}, store::{JsonStore, Stored}};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Scene {
+    #[serde(default)]
+    pub id: u64,
+    pub name: String,
+    /// Pedal config array for each channel
+    pub boards: [Value; CHANNELS],
+    #[serde(default)]
+    pub mixer: MixerState,
+    /// Pedal config array for each aux bus (null in scenes saved before buses existed)
+    #[serde(default)]
+    pub buses: [Value; BUSES],
+}
+
+impl Stored for Scene {
+    const KIND: &'static str = "scene";
+    fn id(&self) -> u64 {
+        self.id
+    }
+    fn set_id(&mut self, id: u64) {
+        self.id = id;
+    }
+}
+
+pub type SceneStore = JsonStore<Scene>;
+
+#[cfg(test)]
+mod test_scenes {
+    use super::*;
+    use serde_json::json;
+    use crate::utils::test_utils::TempDir;
+
+    #[test]
+    fn save_and_get() {
+        let dir = TempDir::new("scenes");
+        let store = SceneStore::new(dir.path().to_path_buf()).unwrap();
+        // Only the name and boards are needed
+        let scene: Scene = serde_json::from_value(json!({ "name": "verse", "boards": [[], []] })).unwrap();
+        assert_eq!(scene.mixer, MixerState::default());
+        let saved = store.save(scene).unwrap();
+        assert_eq!(saved.id, 1);
+        assert_eq!(store.get(1).unwrap(), saved);
+    }
+}
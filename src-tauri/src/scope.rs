@@ -0,0 +1,167 @@
+//! Oscilloscope style snapshots of the audio at the various tap points in the engine.
+//!
+//! Each tap keeps a short history of samples in a ring buffer.  When a snapshot is
+//! requested the last N milliseconds are reduced to min/max pairs so the u/x can draw
+//! a waveform without us shipping raw audio over the event channel.
+//!
+//! Note that pedal-board only exposes processing of the whole board, so the taps are
+//! the board input, the board output (both sides mixed for a stereo board) and the
+//! master (what goes to the alsa device).
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{alsa_thread::{CHANNELS, SAMPLE_RATE}, error::FxError};
+
+/// How much history each tap keeps
+pub const SCOPE_MAX_MS: usize = 2000;
+/// Number of min/max pairs returned when the caller does not ask for a specific count
+pub const SCOPE_DEFAULT_POINTS: usize = 256;
+const SCOPE_SAMPLES: usize = SAMPLE_RATE as usize * SCOPE_MAX_MS / 1000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScopeTap {
+    Input,
+    Output,
+    Master,
+}
+
+impl FromStr for ScopeTap {
+    type Err = FxError;
+    fn from_str(s: &str) -> Result<ScopeTap, FxError> {
+        match s {
+            "input" => Ok(ScopeTap::Input),
+            "output" => Ok(ScopeTap::Output),
+            "master" => Ok(ScopeTap::Master),
+            _ => Err(FxError::ProtocolParse(format!("unknown scope tap: {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for ScopeTap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScopeTap::Input => write!(f, "input"),
+            ScopeTap::Output => write!(f, "output"),
+            ScopeTap::Master => write!(f, "master"),
+        }
+    }
+}
+
+/// Ring buffer holding the most recent samples for a single tap
+pub struct ScopeBuffer {
+    buf: Vec<f32>,
+    pos: usize,
+}
+
+impl ScopeBuffer {
+    pub fn new() -> ScopeBuffer {
+        ScopeBuffer {
+            buf: vec![0.0; SCOPE_SAMPLES],
+            pos: 0,
+        }
+    }
+
+    /// Push a frame of samples into the history
+    pub fn add_frame(&mut self, samples: &[f32]) {
+        for v in samples {
+            self.buf[self.pos] = *v;
+            self.pos = (self.pos + 1) % self.buf.len();
+        }
+    }
+
+    /// Reduce the last `num_samples` samples into `points` min/max pairs (oldest first)
+    pub fn snapshot(&self, num_samples: usize, points: usize) -> Vec<[f32; 2]> {
+        let num_samples = num_samples.clamp(1, self.buf.len());
+        let points = points.clamp(1, num_samples);
+        let start = (self.pos + self.buf.len() - num_samples) % self.buf.len();
+        let mut result = Vec::with_capacity(points);
+        for p in 0..points {
+            let from = p * num_samples / points;
+            let to = (p + 1) * num_samples / points;
+            let mut min = f32::MAX;
+            let mut max = f32::MIN;
+            for i in from..to {
+                let v = self.buf[(start + i) % self.buf.len()];
+                min = min.min(v);
+                max = max.max(v);
+            }
+            result.push([min, max]);
+        }
+        result
+    }
+}
+
+/// All the taps for the board set
+pub struct Scope {
+    inputs: [ScopeBuffer; CHANNELS],
+    outputs: [ScopeBuffer; CHANNELS],
+    master: [ScopeBuffer; CHANNELS],
+}
+
+impl Scope {
+    pub fn new() -> Scope {
+        Scope {
+            inputs: [ScopeBuffer::new(), ScopeBuffer::new()],
+            outputs: [ScopeBuffer::new(), ScopeBuffer::new()],
+            master: [ScopeBuffer::new(), ScopeBuffer::new()],
+        }
+    }
+
+    pub fn tap(&mut self, tap: ScopeTap, channel: usize) -> &mut ScopeBuffer {
+        match tap {
+            ScopeTap::Input => &mut self.inputs[channel],
+            ScopeTap::Output => &mut self.outputs[channel],
+            ScopeTap::Master => &mut self.master[channel],
+        }
+    }
+
+    /// Build the scopeEvent for the last `millis` milliseconds of a tap
+    pub fn snapshot(&mut self, tap: ScopeTap, channel: usize, millis: usize, points: usize) -> Result<Value, FxError> {
+        if channel >= CHANNELS {
+            return Err(FxError::IndexOutOfRange(format!("scope channel {}", channel)));
+        }
+        let millis = millis.clamp(1, SCOPE_MAX_MS);
+        let num_samples = SAMPLE_RATE as usize * millis / 1000;
+        let points = if points == 0 { SCOPE_DEFAULT_POINTS } else { points };
+        let data = self.tap(tap, channel).snapshot(num_samples, points);
+        Ok(json!({
+            "scopeEvent": {
+                "tap": tap.to_string(),
+                "channel": channel,
+                "milliseconds": millis,
+                "sampleRate": SAMPLE_RATE,
+                "points": data,
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test_scope {
+    use super::*;
+
+    #[test]
+    fn snapshot_min_max() {
+        let mut buf = ScopeBuffer::new();
+        buf.add_frame(&[0.0, 1.0, -1.0, 0.5, 0.25, -0.25]);
+        let snap = buf.snapshot(6, 2);
+        assert_eq!(snap, vec![[-1.0, 1.0], [-0.25, 0.5]]);
+    }
+    #[test]
+    fn snapshot_wraps() {
+        let mut buf = ScopeBuffer::new();
+        buf.add_frame(&vec![0.5; SCOPE_SAMPLES - 2]);
+        buf.add_frame(&[0.1, 0.2, 0.3, 0.4]);
+        let snap = buf.snapshot(4, 1);
+        assert_eq!(snap, vec![[0.1, 0.4]]);
+    }
+    #[test]
+    fn tap_names() {
+        assert_eq!(ScopeTap::from_str("master").unwrap(), ScopeTap::Master);
+        assert!(ScopeTap::from_str("pedal").is_err());
+    }
+}
@@ -0,0 +1,160 @@
+//! The last session: devices, boards, mixer and tempo as they were when the app was last
+//! used.  The audio thread sends a [`SessionSnapshot`](SessionSnapshot) a couple of
+//! seconds after the last edit and a writer thread saves it to `session.json`.  When
+//! the engine is started the session is recalled unless auto-restore is turned off.
+
+use std::{path::PathBuf, sync::{Arc, Mutex}};
+
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{alsa_thread::CHANNELS, error::FxError, mixer::{MixerState, BUSES}, tempo::{TempoLock, TimeSignature, DEFAULT_BPM}, utils::{read_json, write_json}};
+
+/// How long the boards must stay unchanged before the session is written (microseconds)
+pub const SESSION_DEBOUNCE: u128 = 2_000_000;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    #[serde(default = "auto_restore_default")]
+    pub auto_restore: bool,
+    #[serde(default)]
+    pub in_dev: Option<String>,
+    #[serde(default)]
+    pub out_dev: Option<String>,
+    /// Pedal config array for each channel (null before anything was saved)
+    #[serde(default)]
+    pub boards: [Value; CHANNELS],
+    #[serde(default)]
+    pub mixer: MixerState,
+    /// Pedal config array for each aux bus
+    #[serde(default)]
+    pub buses: [Value; BUSES],
+    #[serde(default = "bpm_default")]
+    pub bpm: f64,
+    #[serde(default)]
+    pub time_signature: TimeSignature,
+    #[serde(default)]
+    pub tempo_locks: [TempoLock; CHANNELS],
+}
+
+fn bpm_default() -> f64 {
+    DEFAULT_BPM
+}
+
+fn auto_restore_default() -> bool {
+    true
+}
+
+impl Default for Session {
+    fn default() -> Session {
+        Session {
+            auto_restore: true,
+            in_dev: None,
+            out_dev: None,
+            boards: Default::default(),
+            mixer: MixerState::default(),
+            buses: Default::default(),
+            bpm: DEFAULT_BPM,
+            time_signature: TimeSignature::default(),
+            tempo_locks: Default::default(),
+        }
+    }
+}
+
+impl Session {
+    /// True when there are boards worth restoring
+    pub fn has_boards(&self) -> bool {
+        self.boards.iter().all(|board| board.is_array())
+    }
+}
+
+/// What the audio thread sends when the boards, mixer or tempo change
+pub struct SessionSnapshot {
+    pub boards: [Value; CHANNELS],
+    pub mixer: MixerState,
+    pub buses: [Value; BUSES],
+    pub bpm: f64,
+    pub time_signature: TimeSignature,
+    pub tempo_locks: [TempoLock; CHANNELS],
+}
+
+/// The session file.  Clones share a lock so the writer thread and the tauri commands
+/// don't trip over each other.
+#[derive(Clone)]
+pub struct SessionStore {
+    path: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+impl SessionStore {
+    pub fn new(path: PathBuf) -> SessionStore {
+        SessionStore {
+            path,
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// The saved session, or the default if there is none yet or it can't be parsed
+    pub fn load(&self) -> Result<Session, FxError> {
+        let _guard = self.lock.lock().unwrap();
+        self.read()
+    }
+
+    /// Change the saved session
+    pub fn update<F: FnOnce(&mut Session)>(&self, change: F) -> Result<Session, FxError> {
+        let _guard = self.lock.lock().unwrap();
+        let mut session = self.read()?;
+        change(&mut session);
+        write_json(&self.path, &session)?;
+        Ok(session)
+    }
+
+    fn read(&self) -> Result<Session, FxError> {
+        if !self.path.exists() {
+            return Ok(Session::default());
+        }
+        match read_json(&self.path) {
+            // The session is a convenience, a broken file must not keep the engine from starting
+            Err(FxError::ProtocolParse(e)) => {
+                error!("session file {} can't be read, starting fresh: {}", self.path.display(), e);
+                Ok(Session::default())
+            }
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_session {
+    use super::*;
+    use serde_json::json;
+    use crate::utils::test_utils::TempDir;
+
+    #[test]
+    fn update_and_load() {
+        let dir = TempDir::new("session");
+        let store = SessionStore::new(dir.path().join("session.json"));
+        let session = store.load().unwrap();
+        assert!(session.auto_restore);
+        assert!(!session.has_boards());
+        store
+            .update(|s| {
+                s.in_dev = Some(String::from("hw:CODEC"));
+                s.boards = [json!([]), json!([{ "name": "Delay", "settings": [] }])];
+            })
+            .unwrap();
+        store.update(|s| s.auto_restore = false).unwrap();
+        let session = store.load().unwrap();
+        assert_eq!(session.in_dev.as_deref(), Some("hw:CODEC"));
+        assert!(session.has_boards());
+        assert!(!session.auto_restore);
+
+        // A half written file is the same as no session
+        std::fs::write(dir.path().join("session.json"), "{ \"inDev\": ").unwrap();
+        assert_eq!(store.load().unwrap().in_dev, None);
+        store.update(|s| s.out_dev = Some(String::from("hw:CODEC"))).unwrap();
+        assert_eq!(store.load().unwrap().out_dev.as_deref(), Some("hw:CODEC"));
+    }
+}
@@ -0,0 +1,167 @@
+//! Setlists: ordered lists of songs where each song names the preset to load on each
+//! channel.  Setlists are kept in a [`JsonStore`](crate::store::JsonStore) and the
+//! [`SetlistPlayer`](SetlistPlayer) keeps track of where we are in the selected one.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{alsa_thread::CHANNELS, error::FxError, store::{JsonStore, Stored}};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SetlistSong {
+    pub name: String,
+    /// Preset id for each channel, None leaves the channel alone
+    pub presets: [Option<u64>; CHANNELS],
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Setlist {
+    /// 0 (or missing) when saving a new setlist
+    #[serde(default)]
+    pub id: u64,
+    pub name: String,
+    pub songs: Vec<SetlistSong>,
+}
+
+impl Stored for Setlist {
+    const KIND: &'static str = "setlist";
+    fn id(&self) -> u64 {
+        self.id
+    }
+    fn set_id(&mut self, id: u64) {
+        self.id = id;
+    }
+}
+
+pub type SetlistStore = JsonStore<Setlist>;
+
+/// Position in the currently selected setlist
+#[derive(Clone)]
+pub struct SetlistPlayer {
+    setlist: Option<Setlist>,
+    position: usize,
+}
+
+impl SetlistPlayer {
+    pub fn new() -> SetlistPlayer {
+        SetlistPlayer {
+            setlist: None,
+            position: 0,
+        }
+    }
+
+    /// Make a setlist current and go to the first song
+    pub fn select(&mut self, setlist: Setlist) -> Result<&SetlistSong, FxError> {
+        self.setlist = Some(setlist);
+        self.jump(0)
+    }
+
+    /// Go to a song.  Positions past either end are an error.
+    pub fn jump(&mut self, position: usize) -> Result<&SetlistSong, FxError> {
+        let setlist = self.setlist.as_ref().ok_or_else(|| FxError::NotFound(String::from("no setlist selected")))?;
+        let song = setlist
+            .songs
+            .get(position)
+            .ok_or_else(|| FxError::IndexOutOfRange(format!("song {} (setlist has {})", position, setlist.songs.len())))?;
+        self.position = position;
+        Ok(song)
+    }
+
+    /// Pick up a saved copy of the selected setlist.  The position only moves if the set
+    /// got shorter than it.
+    pub fn refresh(&mut self, setlist: &Setlist) {
+        if let Some(current) = self.setlist.as_mut().filter(|current| current.id == setlist.id) {
+            *current = setlist.clone();
+            self.position = self.position.min(setlist.songs.len().saturating_sub(1));
+        }
+    }
+
+    /// Next song, staying on the last one at the end of the set
+    pub fn next(&mut self) -> Result<&SetlistSong, FxError> {
+        let count = self.setlist.as_ref().map_or(0, |s| s.songs.len());
+        self.jump((self.position + 1).min(count.saturating_sub(1)))
+    }
+
+    /// Previous song, staying on the first one at the start of the set
+    pub fn prev(&mut self) -> Result<&SetlistSong, FxError> {
+        self.jump(self.position.saturating_sub(1))
+    }
+
+    pub fn as_json(&self) -> Value {
+        match &self.setlist {
+            Some(setlist) => json!({
+                "setlistEvent": {
+                    "setlistId": setlist.id,
+                    "name": setlist.name,
+                    "position": self.position,
+                    "count": setlist.songs.len(),
+                    "song": setlist.songs.get(self.position),
+                }
+            }),
+            None => json!({ "setlistEvent": null }),
+        }
+    }
+}
+
+/// The store plus the navigation state, managed together by tauri
+pub struct Setlists {
+    pub store: SetlistStore,
+    pub player: SetlistPlayer,
+}
+
+#[cfg(test)]
+mod test_setlists {
+    use super::*;
+    use crate::utils::test_utils::TempDir;
+
+    fn setlist() -> Setlist {
+        let song = |name: &str, a: u64| SetlistSong { name: String::from(name), presets: [Some(a), None] };
+        Setlist {
+            id: 0,
+            name: String::from("friday"),
+            songs: vec![song("one", 1), song("two", 2), song("three", 3)],
+        }
+    }
+
+    #[test]
+    fn navigation() {
+        let mut player = SetlistPlayer::new();
+        assert!(player.next().is_err());
+        assert_eq!(player.select(setlist()).unwrap().name, "one");
+        assert_eq!(player.prev().unwrap().name, "one");
+        assert_eq!(player.next().unwrap().name, "two");
+        assert_eq!(player.next().unwrap().name, "three");
+        assert_eq!(player.next().unwrap().name, "three");
+        assert!(player.jump(3).is_err());
+        assert_eq!(player.jump(0).unwrap().presets, [Some(1), None]);
+        assert_eq!(player.as_json()["setlistEvent"]["position"], 0);
+        // A copy moves on its own, so a failed load can leave the player where it was
+        let mut moved = player.clone();
+        moved.next().unwrap();
+        assert_eq!(player.as_json()["setlistEvent"]["position"], 0);
+
+        // Saving the selected setlist changes what next steps through
+        let mut edited = setlist();
+        edited.songs[1].name = String::from("two (acoustic)");
+        player.refresh(&edited);
+        assert_eq!(player.next().unwrap().name, "two (acoustic)");
+        edited.songs.truncate(1);
+        player.refresh(&edited);
+        assert_eq!(player.as_json()["setlistEvent"]["position"], 0);
+        // Other setlists don't touch it
+        player.refresh(&Setlist { id: 7, ..setlist() });
+        assert_eq!(player.as_json()["setlistEvent"]["count"], 1);
+    }
+    #[test]
+    fn store() {
+        let dir = TempDir::new("setlists");
+        let store = SetlistStore::new(dir.path().to_path_buf()).unwrap();
+        let saved = store.save(setlist()).unwrap();
+        assert_eq!(saved.id, 1);
+        assert_eq!(store.list().unwrap(), vec![saved.clone()]);
+        store.delete(saved.id).unwrap();
+        assert!(store.get(saved.id).is_err());
+    }
+}
@@ -0,0 +1,670 @@
+//! A board built around (mono) `PedalBoard`s, with stereo and parallel paths.
+//!
+//! The board runs its chain as a list of stages.  Runs of ordinary pedals become a
+//! `PedalBoard` (one per side once the chain is stereo); two kinds of pseudo pedal shape
+//! the path:
+//!
+//! - A Stereo Widener is where the chain goes stereo.  Pedals before it run once in mono,
+//!   the widener spreads the signal into left and right (the right side is delayed by
+//!   `spread` ms) and everything after it runs once per side, so reverbs, delays and
+//!   chorus after the widener keep a stereo image.
+//! - A Parallel Split runs its two branches on the same signal and sums them at their
+//!   own levels, e.g. a dry path next to a wet delay.  The branches are in the pedal's
+//!   json as `"branches": [[pedals...], [pedals...]]` and only hold ordinary pedals.
+//!
+//! Pseudo pedals are saved in the config like any other pedal so presets, scenes and
+//! undo carry them.  They never reach pedal-board itself.
+//!
+//! Edits to ordinary pedals go straight to the running boards, which keep their state
+//! (delay and reverb tails) and are where the config is read back from.  Only adding,
+//! removing or moving a pseudo pedal rebuilds the stages.
+
+use pedal_board::PedalBoard;
+use serde_json::{json, Value};
+
+use crate::alsa_thread::{FRAME_SIZE, SAMPLE_RATE};
+
+pub const WIDENER: &str = "Stereo Widener";
+pub const SPLIT: &str = "Parallel Split";
+/// Paths in a split
+pub const BRANCHES: usize = 2;
+/// Longest right side delay (ms)
+const MAX_SPREAD: f32 = 30.0;
+const DEFAULT_SPREAD: f32 = 12.0;
+/// Branch level settings, one per branch
+const BRANCH_LEVELS: [&str; BRANCHES] = ["level a", "level b"];
+/// Two equal branches sum to about where the signal was
+const DEFAULT_BRANCH_LEVEL: f32 = -6.0;
+
+/// The point where the chain goes stereo
+struct Widener {
+    /// Right side delay in ms
+    spread: f32,
+    delay: Vec<f32>,
+    pos: usize,
+}
+
+impl Widener {
+    fn new(spread: f32) -> Widener {
+        Widener {
+            spread: spread.clamp(0.0, MAX_SPREAD),
+            delay: vec![0.0; (MAX_SPREAD / 1000.0 * SAMPLE_RATE as f32) as usize + 1],
+            pos: 0,
+        }
+    }
+
+    /// Make the right side from the mono signal
+    fn process(&mut self, input: &[f32], right: &mut [f32]) {
+        let lag = (self.spread / 1000.0 * SAMPLE_RATE as f32) as usize;
+        let len = self.delay.len();
+        for (sample, out) in input.iter().zip(right.iter_mut()) {
+            self.delay[self.pos] = *sample;
+            *out = self.delay[(self.pos + len - lag) % len];
+            self.pos = (self.pos + 1) % len;
+        }
+    }
+}
+
+/// One path through a split
+struct Branch {
+    /// Linear gain into the merge
+    gain: f32,
+    /// Number of pedals, an empty branch passes the signal through
+    pedals: usize,
+    /// A board per side
+    sides: Vec<PedalBoard>,
+}
+
+enum Stage {
+    /// A run of ordinary pedals, with a board per side
+    Pedals { len: usize, sides: Vec<PedalBoard> },
+    /// `pedal` is the widener's json
+    Widen { pedal: Value, widener: Widener },
+    /// `pedal` is the split's json without the branches, those are read from their boards
+    Split { pedal: Value, branches: [Branch; BRANCHES] },
+}
+
+impl Stage {
+    /// Number of pedals in the chain the stage stands for
+    fn len(&self) -> usize {
+        match self {
+            Stage::Pedals { len, .. } => *len,
+            _ => 1,
+        }
+    }
+}
+
+fn setting_json(index: usize, name: &str, min: f32, max: f32, value: f32) -> Value {
+    json!({
+        "index": index,
+        "labels": [],
+        "max": max,
+        "min": min,
+        "name": name,
+        "step": 0.5,
+        "type": 0,
+        "value": value,
+    })
+}
+
+/// Value of a setting on a pedal's json
+fn setting_value(pedal: &Value, name: &str) -> Option<f32> {
+    pedal["settings"]
+        .as_array()?
+        .iter()
+        .find(|setting| setting["name"] == name)?["value"]
+        .as_f64()
+        .map(|value| value as f32)
+}
+
+/// Store a `{ "name": ..., "value": ... }` setting on a pseudo pedal's json
+fn set_value(pedal: &mut Value, setting: &Value) {
+    let Some(settings) = pedal["settings"].as_array_mut() else { return };
+    if let Some(current) = settings.iter_mut().find(|current| current["name"] == setting["name"]) {
+        let value = match (setting["value"].as_f64(), current["min"].as_f64(), current["max"].as_f64()) {
+            (Some(value), Some(min), Some(max)) => json!(value.clamp(min, max)),
+            _ => setting["value"].clone(),
+        };
+        current["value"] = value;
+    }
+}
+
+fn db_to_gain(level: f32) -> f32 {
+    10f32.powf(level / 20.0)
+}
+
+// Number pedals from 0
+fn renumber(pedals: &mut [Value]) {
+    for (idx, pedal) in pedals.iter_mut().enumerate() {
+        if let Some(pedal) = pedal.as_object_mut() {
+            pedal.insert(String::from("index"), json!(idx));
+        }
+    }
+}
+
+// A board per side running these pedals
+fn boards(channel: usize, stereo: bool, pedals: &[Value]) -> Vec<PedalBoard> {
+    let mut pedals = pedals.to_vec();
+    renumber(&mut pedals);
+    let config = Value::Array(pedals).to_string();
+    let count = if stereo { 2 } else { 1 };
+    (0..count)
+        .map(|_| {
+            let mut board = PedalBoard::new(channel);
+            board.load_from_json(&config);
+            board
+        })
+        .collect()
+}
+
+// The pedals a board is running
+fn effects(board: &PedalBoard, channel: usize) -> Vec<Value> {
+    board.as_json(channel)["effects"].as_array().cloned().unwrap_or_default()
+}
+
+// Run a board over the signal in place
+fn run(board: &mut PedalBoard, work: &mut [f32], tmp: &mut [f32]) {
+    board.process(work, tmp);
+    work.copy_from_slice(tmp);
+}
+
+// Run every branch on the signal and sum them back into it
+fn merge(branches: &mut [Branch], side: usize, work: &mut [f32], tmp: &mut [f32], mix: &mut [f32]) {
+    mix.fill(0.0);
+    for branch in branches.iter_mut() {
+        let out: &[f32] = if branch.pedals == 0 {
+            work
+        } else {
+            branch.sides[side].process(work, tmp);
+            tmp
+        };
+        for (sum, sample) in mix.iter_mut().zip(out.iter()) {
+            *sum += branch.gain * sample;
+        }
+    }
+    work.copy_from_slice(mix);
+}
+
+pub struct StereoBoard {
+    channel: usize,
+    stages: Vec<Stage>,
+    stereo: bool,
+    work: [Vec<f32>; 2],
+    tmp: Vec<f32>,
+    mix: Vec<f32>,
+}
+
+impl StereoBoard {
+    pub fn new(channel: usize) -> StereoBoard {
+        StereoBoard {
+            channel,
+            stages: Vec::new(),
+            stereo: false,
+            work: [vec![0.0; FRAME_SIZE], vec![0.0; FRAME_SIZE]],
+            tmp: vec![0.0; FRAME_SIZE],
+            mix: vec![0.0; FRAME_SIZE],
+        }
+    }
+
+    /// pedal-board's pedal types plus the pseudo pedals
+    pub fn get_pedal_types() -> Value {
+        let mut types = json!(PedalBoard::get_pedal_types());
+        if let Some(types) = types.as_object_mut() {
+            types.insert(String::from(WIDENER), json!(WIDENER));
+            types.insert(String::from(SPLIT), json!(SPLIT));
+        }
+        types
+    }
+
+    /// Json for a new pedal of a type, with its default settings
+    pub fn new_pedal(&self, type_name: &str) -> Value {
+        match type_name {
+            WIDENER => json!({
+                "name": WIDENER,
+                "settings": [setting_json(0, "spread", 0.0, MAX_SPREAD, DEFAULT_SPREAD)],
+            }),
+            SPLIT => json!({
+                "name": SPLIT,
+                "settings": BRANCH_LEVELS
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, name)| setting_json(idx, name, -60.0, 6.0, DEFAULT_BRANCH_LEVEL))
+                    .collect::<Vec<Value>>(),
+                "branches": [[], []],
+            }),
+            _ => {
+                // Let pedal-board fill in the settings
+                let mut board = PedalBoard::new(self.channel);
+                board.insert_pedal(type_name, 0);
+                match effects(&board, self.channel).first() {
+                    Some(pedal) if pedal.is_object() => pedal.clone(),
+                    _ => json!({ "name": type_name, "settings": [] }),
+                }
+            }
+        }
+    }
+
+    pub fn is_stereo(&self) -> bool {
+        self.stereo
+    }
+
+    /// No pedals at all, the board passes its input straight through
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    pub fn load_from_json(&mut self, data: &str) {
+        let mut chain = serde_json::from_str::<Value>(data)
+            .ok()
+            .and_then(|config| config.as_array().cloned())
+            .unwrap_or_default();
+        // Only one widener counts
+        if let Some(first) = chain.iter().position(|pedal| pedal["name"] == WIDENER) {
+            let mut idx = 0;
+            chain.retain(|pedal| {
+                idx += 1;
+                idx - 1 == first || pedal["name"] != WIDENER
+            });
+        }
+        self.build(chain);
+    }
+
+    /// Same shape as `PedalBoard::as_json`
+    pub fn as_json(&self, idx: usize) -> Value {
+        json!({
+            "boardId": idx,
+            "effects": self.chain(),
+        })
+    }
+
+    /// The chain as the u/x sees it, read back from the running boards
+    fn chain(&self) -> Vec<Value> {
+        let mut chain = Vec::new();
+        for stage in self.stages.iter() {
+            match stage {
+                Stage::Pedals { sides, .. } => chain.extend(effects(&sides[0], self.channel)),
+                Stage::Widen { pedal, .. } => chain.push(pedal.clone()),
+                Stage::Split { pedal, branches } => {
+                    let mut pedal = pedal.clone();
+                    pedal["branches"] = branches
+                        .iter()
+                        .map(|branch| Value::Array(effects(&branch.sides[0], self.channel)))
+                        .collect();
+                    chain.push(pedal);
+                }
+            }
+        }
+        renumber(&mut chain);
+        chain
+    }
+
+    /// Number of pedals in the chain
+    fn pedal_count(&self) -> usize {
+        self.stages.iter().map(Stage::len).sum()
+    }
+
+    /// Stage holding the pedal at `index` and where the pedal is in it
+    fn locate(&self, index: usize) -> Option<(usize, usize)> {
+        let mut start = 0;
+        for (idx, stage) in self.stages.iter().enumerate() {
+            if index < start + stage.len() {
+                return Some((idx, index - start));
+            }
+            start += stage.len();
+        }
+        None
+    }
+
+    /// Whether a stage runs after the widener
+    fn stereo_at(&self, stage: usize) -> bool {
+        self.stages[..stage].iter().any(|stage| matches!(stage, Stage::Widen { .. }))
+    }
+
+    /// Run of pedals an ordinary pedal inserted at `index` goes into, and where in it.
+    /// Starts a new run when the pedal lands between two pseudo pedals.
+    fn pedals_at(&mut self, index: usize) -> (usize, usize) {
+        let mut start = 0;
+        let mut at = self.stages.len();
+        for (idx, stage) in self.stages.iter().enumerate() {
+            if let Stage::Pedals { len, .. } = stage {
+                if index <= start + len {
+                    return (idx, index - start);
+                }
+            }
+            if index <= start {
+                at = idx;
+                break;
+            }
+            start += stage.len();
+        }
+        let sides = boards(self.channel, self.stereo_at(at), &[]);
+        self.stages.insert(at, Stage::Pedals { len: 0, sides });
+        (at, 0)
+    }
+
+    fn split(&self, mut pedal: Value, stereo: bool) -> Stage {
+        let branches = std::array::from_fn(|branch| {
+            let pedals = pedal["branches"][branch].as_array().cloned().unwrap_or_default();
+            Branch {
+                gain: db_to_gain(setting_value(&pedal, BRANCH_LEVELS[branch]).unwrap_or(DEFAULT_BRANCH_LEVEL)),
+                pedals: pedals.len(),
+                sides: boards(self.channel, stereo, &pedals),
+            }
+        });
+        if let Some(fields) = pedal.as_object_mut() {
+            fields.remove("branches");
+        }
+        Stage::Split { pedal, branches }
+    }
+
+    /// Make the stages for a chain
+    fn build(&mut self, chain: Vec<Value>) {
+        self.stages.clear();
+        self.stereo = false;
+        let mut pedals = Vec::new();
+        for pedal in chain {
+            let stage = match pedal["name"].as_str() {
+                Some(WIDENER) => Stage::Widen {
+                    widener: Widener::new(setting_value(&pedal, "spread").unwrap_or(DEFAULT_SPREAD)),
+                    pedal,
+                },
+                Some(SPLIT) => self.split(pedal, self.stereo),
+                _ => {
+                    pedals.push(pedal);
+                    continue;
+                }
+            };
+            self.push_pedals(&mut pedals);
+            self.stereo |= matches!(stage, Stage::Widen { .. });
+            self.stages.push(stage);
+        }
+        self.push_pedals(&mut pedals);
+    }
+
+    fn push_pedals(&mut self, pedals: &mut Vec<Value>) {
+        if !pedals.is_empty() {
+            let sides = boards(self.channel, self.stereo, pedals);
+            self.stages.push(Stage::Pedals { len: pedals.len(), sides });
+            pedals.clear();
+        }
+    }
+
+    /// Apply an edit that moves a widener or split and build the stages again
+    fn rebuild(&mut self, edit: impl FnOnce(&mut Vec<Value>)) {
+        let mut chain = self.chain();
+        edit(&mut chain);
+        self.build(chain);
+    }
+
+    /// Put a pedal (with its settings, and branches for a split) in the chain
+    pub fn insert_json(&mut self, index: usize, pedal: &Value) {
+        let index = index.min(self.pedal_count());
+        match pedal["name"].as_str() {
+            Some(WIDENER) | Some(SPLIT) => self.rebuild(|chain| chain.insert(index, pedal.clone())),
+            name => {
+                self.insert_pedal(name.unwrap_or_default(), index);
+                for setting in pedal["settings"].as_array().into_iter().flatten() {
+                    self.change_value(index, &json!({ "name": setting["name"], "value": setting["value"] }));
+                }
+            }
+        }
+    }
+
+    /// Swap a pedal's json for another, e.g. a split with edited branches
+    pub fn replace(&mut self, index: usize, pedal: &Value) {
+        match self.locate(index) {
+            Some((stage, _)) if pedal["name"] == SPLIT && matches!(self.stages[stage], Stage::Split { .. }) => {
+                self.stages[stage] = self.split(pedal.clone(), self.stereo_at(stage));
+            }
+            Some(_) => self.rebuild(|chain| chain[index] = pedal.clone()),
+            None => {}
+        }
+    }
+
+    pub fn insert_pedal(&mut self, type_name: &str, index: usize) {
+        let index = index.min(self.pedal_count());
+        if type_name == WIDENER || type_name == SPLIT {
+            let pedal = self.new_pedal(type_name);
+            self.rebuild(|chain| chain.insert(index, pedal));
+            return;
+        }
+        let (stage, position) = self.pedals_at(index);
+        if let Stage::Pedals { len, sides } = &mut self.stages[stage] {
+            for side in sides.iter_mut() {
+                side.insert_pedal(type_name, position);
+            }
+            *len += 1;
+        }
+    }
+
+    pub fn delete_pedal(&mut self, index: usize) {
+        let Some((stage, position)) = self.locate(index) else { return };
+        if let Stage::Pedals { len, sides } = &mut self.stages[stage] {
+            for side in sides.iter_mut() {
+                side.delete_pedal(position);
+            }
+            *len -= 1;
+            if *len == 0 {
+                self.stages.remove(stage);
+            }
+        } else {
+            self.rebuild(|chain| {
+                chain.remove(index);
+            });
+        }
+    }
+
+    pub fn move_pedal(&mut self, from: usize, to: usize) {
+        let (Some((stage, position)), Some((target, target_position))) = (self.locate(from), self.locate(to)) else {
+            return;
+        };
+        if !matches!(self.stages[stage], Stage::Pedals { .. }) {
+            self.rebuild(|chain| {
+                let pedal = chain.remove(from);
+                chain.insert(to, pedal);
+            });
+        } else if stage == target {
+            if let Stage::Pedals { sides, .. } = &mut self.stages[stage] {
+                for side in sides.iter_mut() {
+                    side.move_pedal(position, target_position);
+                }
+            }
+        } else {
+            // Into another run, past a pseudo pedal
+            let pedal = self.chain()[from].clone();
+            self.delete_pedal(from);
+            self.insert_json(to, &pedal);
+        }
+    }
+
+    pub fn change_value(&mut self, index: usize, setting: &Value) {
+        let Some((stage, position)) = self.locate(index) else { return };
+        match &mut self.stages[stage] {
+            Stage::Pedals { sides, .. } => {
+                for side in sides.iter_mut() {
+                    side.change_value(position, setting);
+                }
+            }
+            Stage::Widen { pedal, widener } => {
+                set_value(pedal, setting);
+                widener.spread = setting_value(pedal, "spread").unwrap_or(widener.spread);
+            }
+            Stage::Split { pedal, branches } => {
+                set_value(pedal, setting);
+                for (branch, name) in branches.iter_mut().zip(BRANCH_LEVELS) {
+                    branch.gain = setting_value(pedal, name).map_or(branch.gain, db_to_gain);
+                }
+            }
+        }
+    }
+
+    /// Number of pedals on one branch of the split at `index`
+    pub fn branch_len(&self, index: usize, branch: usize) -> Option<usize> {
+        let (stage, _) = self.locate(index)?;
+        match &self.stages[stage] {
+            Stage::Split { branches, .. } => branches.get(branch).map(|branch| branch.pedals),
+            _ => None,
+        }
+    }
+
+    fn branch_mut(&mut self, index: usize, branch: usize) -> Option<&mut Branch> {
+        let (stage, _) = self.locate(index)?;
+        match &mut self.stages[stage] {
+            Stage::Split { branches, .. } => branches.get_mut(branch),
+            _ => None,
+        }
+    }
+
+    pub fn insert_branch_pedal(&mut self, index: usize, branch: usize, position: usize, type_name: &str) {
+        if let Some(branch) = self.branch_mut(index, branch) {
+            let position = position.min(branch.pedals);
+            for side in branch.sides.iter_mut() {
+                side.insert_pedal(type_name, position);
+            }
+            branch.pedals += 1;
+        }
+    }
+
+    pub fn delete_branch_pedal(&mut self, index: usize, branch: usize, position: usize) {
+        if let Some(branch) = self.branch_mut(index, branch).filter(|branch| position < branch.pedals) {
+            for side in branch.sides.iter_mut() {
+                side.delete_pedal(position);
+            }
+            branch.pedals -= 1;
+        }
+    }
+
+    pub fn move_branch_pedal(&mut self, index: usize, branch: usize, from: usize, to: usize) {
+        if let Some(branch) = self.branch_mut(index, branch).filter(|branch| from < branch.pedals && to < branch.pedals) {
+            for side in branch.sides.iter_mut() {
+                side.move_pedal(from, to);
+            }
+        }
+    }
+
+    pub fn change_branch_value(&mut self, index: usize, branch: usize, position: usize, setting: &Value) {
+        if let Some(branch) = self.branch_mut(index, branch).filter(|branch| position < branch.pedals) {
+            for side in branch.sides.iter_mut() {
+                side.change_value(position, setting);
+            }
+        }
+    }
+
+    /// Run a frame through the board.  A mono board puts the same signal on both sides.
+    pub fn process(&mut self, input: &[f32], left: &mut [f32], right: &mut [f32]) {
+        let [work_l, work_r] = &mut self.work;
+        work_l.copy_from_slice(input);
+        for stage in self.stages.iter_mut() {
+            match stage {
+                Stage::Pedals { sides, .. } => {
+                    run(&mut sides[0], work_l, &mut self.tmp);
+                    if let Some(side) = sides.get_mut(1) {
+                        run(side, work_r, &mut self.tmp);
+                    }
+                }
+                Stage::Widen { widener, .. } => widener.process(work_l, work_r),
+                Stage::Split { branches, .. } => {
+                    merge(branches, 0, work_l, &mut self.tmp, &mut self.mix);
+                    if branches[0].sides.len() > 1 {
+                        merge(branches, 1, work_r, &mut self.tmp, &mut self.mix);
+                    }
+                }
+            }
+        }
+        left.copy_from_slice(work_l);
+        right.copy_from_slice(if self.stereo { work_r } else { work_l });
+    }
+}
+
+#[cfg(test)]
+mod test_stereo_board {
+    use super::*;
+
+    const DELAY: &str = "Delay";
+    const REVERB: &str = "Sigma Reverb";
+
+    #[test]
+    fn widener_delays_the_right_side() {
+        let mut board = StereoBoard::new(0);
+        board.insert_pedal(WIDENER, 0);
+        board.change_value(0, &json!({ "name": "spread", "value": 1.0 }));
+        assert!(board.is_stereo());
+        assert_eq!(setting_value(&board.as_json(0)["effects"][0], "spread"), Some(1.0));
+        let lag = SAMPLE_RATE as usize / 1000;
+        let mut input = vec![0.0; FRAME_SIZE];
+        input[0] = 1.0;
+        let (mut left, mut right) = (vec![0.0; FRAME_SIZE], vec![0.0; FRAME_SIZE]);
+        board.process(&input, &mut left, &mut right);
+        assert_eq!(left, input);
+        assert_eq!(right[lag], 1.0);
+        assert_eq!(right.iter().sum::<f32>(), 1.0);
+    }
+
+    #[test]
+    fn split_sums_its_branches() {
+        let mut board = StereoBoard::new(0);
+        board.insert_pedal(SPLIT, 0);
+        board.change_value(0, &json!({ "name": "level a", "value": 0.0 }));
+        board.change_value(0, &json!({ "name": "level b", "value": -100.0 }));
+        // Levels are held to the setting's range
+        assert_eq!(setting_value(&board.as_json(0)["effects"][0], "level b"), Some(-60.0));
+        let input = vec![0.5; FRAME_SIZE];
+        let (mut left, mut right) = (vec![0.0; FRAME_SIZE], vec![0.0; FRAME_SIZE]);
+        board.process(&input, &mut left, &mut right);
+        assert!((left[0] - 0.5 * (1.0 + db_to_gain(-60.0))).abs() < 1e-6);
+        assert_eq!(left, right);
+
+        // Round trip through the config keeps the split, and the widener moves with it
+        board.insert_pedal(WIDENER, 0);
+        board.move_pedal(0, 1);
+        let config = board.as_json(0)["effects"].clone();
+        assert_eq!(config[0]["name"], SPLIT);
+        assert_eq!(config[1]["index"], 1);
+        let mut loaded = StereoBoard::new(1);
+        loaded.load_from_json(&config.to_string());
+        assert!(loaded.is_stereo());
+        assert_eq!(loaded.branch_len(0, 1), Some(0));
+        assert!(loaded.branch_len(1, 0).is_none());
+
+        // Branch pedals are read back from their boards
+        loaded.insert_branch_pedal(0, 1, 0, DELAY);
+        loaded.change_branch_value(0, 1, 0, &json!({ "name": "level", "value": 0.25 }));
+        assert_eq!(loaded.branch_len(0, 1), Some(1));
+        let branch = &loaded.as_json(1)["effects"][0]["branches"][1];
+        assert_eq!(branch[0]["name"], DELAY);
+        assert_eq!(setting_value(&branch[0], "level"), Some(0.25));
+    }
+
+    #[test]
+    fn edits_stay_on_the_running_boards() {
+        let types = StereoBoard::get_pedal_types();
+        assert!(types.get(DELAY).is_some() && types.get(REVERB).is_some());
+        let mut board = StereoBoard::new(0);
+        board.insert_pedal(DELAY, 0);
+        board.insert_pedal(REVERB, 1);
+        board.change_value(1, &json!({ "name": "level", "value": 0.25 }));
+        // A mono board is one run of pedals on one board
+        assert!(matches!(&board.stages[..], [Stage::Pedals { len: 2, sides }] if sides.len() == 1));
+        board.move_pedal(1, 0);
+        let config = board.as_json(0)["effects"].clone();
+        assert_eq!(config[0]["name"], REVERB);
+        assert_eq!(setting_value(&config[0], "level"), Some(0.25));
+        assert_eq!(config[1]["index"], 1);
+
+        // Past the widener the pedals run once per side
+        board.insert_pedal(WIDENER, 1);
+        board.move_pedal(0, 2);
+        assert!(matches!(&board.stages[..], [Stage::Widen { .. }, Stage::Pedals { len: 2, sides }] if sides.len() == 2));
+        let reverb = board.as_json(0)["effects"][2].clone();
+        assert_eq!(reverb["name"], REVERB);
+        assert_eq!(setting_value(&reverb, "level"), Some(0.25));
+
+        // Putting a deleted pedal back keeps its settings
+        board.delete_pedal(2);
+        assert_eq!(board.as_json(0)["effects"].as_array().map(Vec::len), Some(2));
+        board.insert_json(2, &reverb);
+        let restored = &board.as_json(0)["effects"][2];
+        assert_eq!(restored["name"], REVERB);
+        assert_eq!(setting_value(restored, "level"), Some(0.25));
+    }
+}
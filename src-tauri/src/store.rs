@@ -0,0 +1,124 @@
+//! Simple on-disk collection of JSON documents, one `<id>.json` file per item.
+
+use std::{fs, marker::PhantomData, path::PathBuf};
+
+use log::error;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{error::FxError, utils::{json_file_ids, read_json, write_json}};
+
+/// Something that can be kept in a [`JsonStore`](JsonStore)
+pub trait Stored: Serialize + DeserializeOwned {
+    /// Used in error messages, e.g. "setlist 3"
+    const KIND: &'static str;
+    fn id(&self) -> u64;
+    fn set_id(&mut self, id: u64);
+    /// Make an item from the json in its file, upgrading it if needed
+    fn from_stored(raw: Value) -> Result<Self, FxError> {
+        Ok(serde_json::from_value(raw)?)
+    }
+}
+
+pub struct JsonStore<T: Stored> {
+    dir: PathBuf,
+    _kind: PhantomData<T>,
+}
+
+impl<T: Stored> JsonStore<T> {
+    pub fn new(dir: PathBuf) -> Result<JsonStore<T>, FxError> {
+        fs::create_dir_all(&dir)?;
+        Ok(JsonStore { dir, _kind: PhantomData })
+    }
+
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    /// Every item that can be read.  A broken file is logged and left out so it can't
+    /// hide the rest.
+    pub fn list(&self) -> Result<Vec<T>, FxError> {
+        let items = json_file_ids(&self.dir)?.into_iter().filter_map(|id| match self.get(id) {
+            Ok(item) => Some(item),
+            Err(e) => {
+                error!("{} {} can't be read: {}", T::KIND, id, e);
+                None
+            }
+        });
+        Ok(items.collect())
+    }
+
+    pub fn get(&self, id: u64) -> Result<T, FxError> {
+        let path = self.path(id);
+        if !path.exists() {
+            return Err(FxError::NotFound(format!("{} {}", T::KIND, id)));
+        }
+        T::from_stored(read_json(&path)?)
+    }
+
+    /// Save an item.  An id of 0 gets the next free id.
+    pub fn save(&self, mut item: T) -> Result<T, FxError> {
+        if item.id() == 0 {
+            item.set_id(json_file_ids(&self.dir)?.last().map_or(1, |id| id + 1));
+        }
+        write_json(&self.path(item.id()), &item)?;
+        Ok(item)
+    }
+
+    pub fn delete(&self, id: u64) -> Result<(), FxError> {
+        let path = self.path(id);
+        if !path.exists() {
+            return Err(FxError::NotFound(format!("{} {}", T::KIND, id)));
+        }
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_store {
+    use super::*;
+    use serde::Deserialize;
+    use crate::utils::test_utils::TempDir;
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Note {
+        id: u64,
+        text: String,
+    }
+
+    impl Stored for Note {
+        const KIND: &'static str = "note";
+        fn id(&self) -> u64 {
+            self.id
+        }
+        fn set_id(&mut self, id: u64) {
+            self.id = id;
+        }
+    }
+
+    fn note(id: u64, text: &str) -> Note {
+        Note { id, text: String::from(text) }
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new("store");
+        let store: JsonStore<Note> = JsonStore::new(dir.path().join("notes")).unwrap();
+        assert_eq!(store.save(note(0, "a")).unwrap().id, 1);
+        let b = store.save(note(0, "b")).unwrap();
+        assert_eq!(b.id, 2);
+        // Saving with an id replaces that item
+        store.save(note(1, "c")).unwrap();
+        assert_eq!(store.list().unwrap(), vec![note(1, "c"), b.clone()]);
+        store.delete(1).unwrap();
+        assert!(matches!(store.get(1), Err(FxError::NotFound(_))));
+        assert!(store.delete(1).is_err());
+        // Another store on the same directory sees what was saved
+        let again: JsonStore<Note> = JsonStore::new(dir.path().join("notes")).unwrap();
+        assert_eq!(again.list().unwrap(), vec![b.clone()]);
+        // A broken file doesn't hide the others
+        fs::write(dir.path().join("notes").join("5.json"), "{ \"id\": 5, ").unwrap();
+        assert_eq!(again.list().unwrap(), vec![b]);
+    }
+}
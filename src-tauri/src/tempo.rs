@@ -0,0 +1,401 @@
+//! Tempo: tap tempo, MIDI clock, and turning note divisions into pedal settings.
+//!
+//! The audio thread owns the [`Tempo`](Tempo).  It is set by tapping, by an external
+//! MIDI clock or directly from the u/x.  The current BPM is also published through a
+//! [`SharedTempo`](SharedTempo) so the MIDI clock output thread can follow it.
+//!
+//! Time based settings can be given as a note division (`"1/8"`, `"1/8d"` dotted,
+//! `"1/8t"` triplet) instead of a number and are converted at the current tempo.  A
+//! board with its [`TempoLock`](TempoLock) enabled keeps those settings in time when
+//! the tempo changes or another preset is loaded.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    str::FromStr,
+    sync::{atomic::{AtomicU64, Ordering}, Arc},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::error::FxError;
+
+pub const DEFAULT_BPM: f64 = 120.0;
+pub const MIN_BPM: f64 = 20.0;
+pub const MAX_BPM: f64 = 300.0;
+/// MIDI clock tempo changes smaller than this (BPM) are jitter
+const CLOCK_THRESHOLD: f64 = 0.5;
+/// MIDI clock pulses per quarter note
+pub const CLOCKS_PER_BEAT: usize = 24;
+/// Taps further apart than this start a new tempo
+const TAP_TIMEOUT: Duration = Duration::from_secs(2);
+/// Number of tap intervals averaged
+const MAX_TAPS: usize = 4;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum TempoSource {
+    #[default]
+    Manual,
+    Tap,
+    MidiClock,
+}
+
+/// BPM readable from any thread
+#[derive(Clone)]
+pub struct SharedTempo(Arc<AtomicU64>);
+
+impl SharedTempo {
+    pub fn new(bpm: f64) -> SharedTempo {
+        SharedTempo(Arc::new(AtomicU64::new(bpm.to_bits())))
+    }
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+    pub fn set(&self, bpm: f64) {
+        self.0.store(bpm.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Average the intervals between recent taps
+pub struct TapTempo {
+    taps: VecDeque<Instant>,
+}
+
+impl TapTempo {
+    pub fn new() -> TapTempo {
+        TapTempo { taps: VecDeque::with_capacity(MAX_TAPS + 1) }
+    }
+
+    /// Register a tap, getting the new BPM once there are two taps
+    pub fn tap(&mut self, now: Instant) -> Option<f64> {
+        // A long pause (or a time from before the last tap) starts over
+        let stale = |last: &Instant| now.checked_duration_since(*last).is_none_or(|since| since > TAP_TIMEOUT);
+        if self.taps.back().is_some_and(stale) {
+            self.taps.clear();
+        }
+        self.taps.push_back(now);
+        if self.taps.len() > MAX_TAPS + 1 {
+            self.taps.pop_front();
+        }
+        let (first, last) = (self.taps.front()?, self.taps.back()?);
+        if self.taps.len() < 2 {
+            return None;
+        }
+        let interval = last.saturating_duration_since(*first).as_secs_f64() / (self.taps.len() - 1) as f64;
+        if interval == 0.0 {
+            return None;
+        }
+        Some(60.0 / interval)
+    }
+}
+
+/// Estimate tempo from incoming MIDI clock pulses
+pub struct MidiClockIn {
+    pulses: VecDeque<Instant>,
+    count: usize,
+}
+
+impl MidiClockIn {
+    pub fn new() -> MidiClockIn {
+        MidiClockIn {
+            pulses: VecDeque::with_capacity(CLOCKS_PER_BEAT + 1),
+            count: 0,
+        }
+    }
+
+    /// Start over (the clock stopped or restarted)
+    pub fn reset(&mut self) {
+        self.pulses.clear();
+        self.count = 0;
+    }
+
+    /// Register a clock pulse.  Gives an estimate averaged over the last beat, once per beat.
+    pub fn pulse(&mut self, now: Instant) -> Option<f64> {
+        self.pulses.push_back(now);
+        if self.pulses.len() > CLOCKS_PER_BEAT + 1 {
+            self.pulses.pop_front();
+        }
+        self.count += 1;
+        if self.pulses.len() <= CLOCKS_PER_BEAT || !self.count.is_multiple_of(CLOCKS_PER_BEAT) {
+            return None;
+        }
+        let beat = self.pulses.back()?.saturating_duration_since(*self.pulses.front()?);
+        if beat.is_zero() {
+            return None;
+        }
+        Some(60.0 / beat.as_secs_f64())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeSignature {
+    pub beats: u32,
+    /// Note value of a beat (4 = quarter note)
+    pub unit: u32,
+}
+
+impl Default for TimeSignature {
+    fn default() -> TimeSignature {
+        TimeSignature { beats: 4, unit: 4 }
+    }
+}
+
+impl TimeSignature {
+    /// Length of a bar in quarter notes
+    pub fn bar_length(&self) -> f64 {
+        self.beats as f64 * 4.0 / self.unit as f64
+    }
+}
+
+/// The global tempo kept by the audio thread
+pub struct Tempo {
+    pub bpm: f64,
+    pub source: TempoSource,
+    pub time_signature: TimeSignature,
+    taps: TapTempo,
+    shared: SharedTempo,
+}
+
+impl Tempo {
+    pub fn new(shared: SharedTempo) -> Tempo {
+        Tempo {
+            bpm: shared.get(),
+            source: TempoSource::Manual,
+            time_signature: TimeSignature::default(),
+            taps: TapTempo::new(),
+            shared,
+        }
+    }
+
+    pub fn set(&mut self, bpm: f64, source: TempoSource) {
+        self.bpm = bpm.clamp(MIN_BPM, MAX_BPM);
+        self.source = source;
+        self.shared.set(self.bpm);
+    }
+
+    /// Follow a tempo estimated from MIDI clock.  The estimate wanders a little from beat
+    /// to beat so it is rounded and small changes are ignored.  Returns true when the
+    /// tempo changed.
+    pub fn follow_clock(&mut self, bpm: f64) -> bool {
+        let bpm = (bpm * 10.0).round() / 10.0;
+        if (bpm - self.bpm).abs() < CLOCK_THRESHOLD {
+            return false;
+        }
+        self.set(bpm, TempoSource::MidiClock);
+        true
+    }
+
+    /// Returns true when the tap changed the tempo
+    pub fn tap(&mut self, now: Instant) -> bool {
+        match self.taps.tap(now) {
+            Some(bpm) => {
+                self.set(bpm, TempoSource::Tap);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Length of a bar in samples
+    pub fn bar_samples(&self, sample_rate: f64) -> usize {
+        (self.time_signature.bar_length() * 60.0 / self.bpm * sample_rate).round() as usize
+    }
+
+    pub fn as_json(&self) -> Value {
+        json!({
+            "tempoEvent": {
+                "bpm": self.bpm,
+                "source": self.source,
+                "timeSignature": self.time_signature,
+            }
+        })
+    }
+}
+
+/// A note length such as `1/4`, `1/8d` (dotted) or `1/8t` (triplet)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoteDivision {
+    /// Length in quarter notes
+    beats: f64,
+}
+
+impl NoteDivision {
+    pub fn millis(&self, bpm: f64) -> f64 {
+        self.beats * 60_000.0 / bpm
+    }
+}
+
+impl FromStr for NoteDivision {
+    type Err = FxError;
+    fn from_str(s: &str) -> Result<NoteDivision, FxError> {
+        let bad = || FxError::ProtocolParse(format!("'{}' is not a note division", s));
+        let (s, factor) = match s.trim() {
+            d if d.ends_with('d') || d.ends_with('.') => (&d[..d.len() - 1], 1.5),
+            t if t.ends_with('t') => (&t[..t.len() - 1], 2.0 / 3.0),
+            n => (n, 1.0),
+        };
+        let (num, den) = s.split_once('/').unwrap_or((s, "1"));
+        let num: f64 = num.trim().parse().map_err(|_| bad())?;
+        let den: f64 = den.trim().parse().map_err(|_| bad())?;
+        if num <= 0.0 || den <= 0.0 {
+            return Err(bad());
+        }
+        Ok(NoteDivision { beats: 4.0 * num / den * factor })
+    }
+}
+
+enum SyncUnit {
+    Millis,
+    Hertz,
+}
+
+/// Settings that can follow the tempo: (pedal name, setting name, unit)
+const SYNCED_SETTINGS: &[(&str, &str, SyncUnit)] = &[
+    ("Delay", "duration", SyncUnit::Millis),
+    ("Tremelo", "rate", SyncUnit::Hertz),
+    ("Chorus", "rate", SyncUnit::Hertz),
+];
+
+/// The setting of a pedal type that can follow the tempo
+fn synced_setting(pedal: &str) -> Option<&'static str> {
+    SYNCED_SETTINGS.iter().find(|(p, _, _)| *p == pedal).map(|(_, s, _)| *s)
+}
+
+/// Which time based settings on a board follow the tempo
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TempoLock {
+    pub enabled: bool,
+    /// Note division for each pedal type, e.g. `{ "Delay": "1/8d" }`
+    pub divisions: BTreeMap<String, String>,
+}
+
+impl Default for TempoLock {
+    fn default() -> TempoLock {
+        let divisions = [("Delay", "1/4"), ("Tremelo", "1/8"), ("Chorus", "1/2")];
+        TempoLock {
+            enabled: false,
+            divisions: divisions.iter().map(|(p, d)| (p.to_string(), d.to_string())).collect(),
+        }
+    }
+}
+
+impl TempoLock {
+    /// The settings to change on a board (pedal index, `{ name, value }`) to put it in
+    /// time.  `effects` is the board's pedal array.
+    pub fn settings(&self, effects: &Value, bpm: f64) -> Result<Vec<(usize, Value)>, FxError> {
+        let mut changes = Vec::new();
+        if !self.enabled {
+            return Ok(changes);
+        }
+        for (index, pedal) in effects.as_array().into_iter().flatten().enumerate() {
+            let pedal_name = pedal["name"].as_str().unwrap_or_default();
+            let (setting_name, division) = match (synced_setting(pedal_name), self.divisions.get(pedal_name)) {
+                (Some(setting), Some(division)) => (setting, division.parse::<NoteDivision>()?),
+                _ => continue,
+            };
+            let setting = pedal["settings"]
+                .as_array()
+                .and_then(|settings| settings.iter().find(|s| s["name"] == setting_name));
+            if let Some(setting) = setting {
+                let value = synced_value(pedal_name, setting, &division, bpm)?;
+                changes.push((index, json!({ "name": setting_name, "value": value })));
+            }
+        }
+        Ok(changes)
+    }
+}
+
+/// The value for a synced setting at a tempo, kept inside the setting's min/max.
+/// `setting` is the setting json from the pedal.
+pub fn synced_value(pedal: &str, setting: &Value, division: &NoteDivision, bpm: f64) -> Result<f64, FxError> {
+    let name = setting["name"].as_str().unwrap_or_default();
+    let unit = SYNCED_SETTINGS
+        .iter()
+        .find(|(p, s, _)| *p == pedal && *s == name)
+        .map(|(_, _, unit)| unit)
+        .ok_or_else(|| FxError::UnknownSetting(format!("{} {} can not follow the tempo", pedal, name)))?;
+    let millis = division.millis(bpm);
+    let value = match unit {
+        SyncUnit::Millis => millis,
+        SyncUnit::Hertz => 1000.0 / millis,
+    };
+    let min = setting["min"].as_f64().unwrap_or(f64::MIN);
+    let max = setting["max"].as_f64().unwrap_or(f64::MAX);
+    Ok(value.clamp(min, max))
+}
+
+#[cfg(test)]
+mod test_tempo {
+    use super::*;
+
+    #[test]
+    fn divisions() {
+        let at = |d: &str| d.parse::<NoteDivision>().unwrap().millis(120.0);
+        assert_eq!(at("1/4"), 500.0);
+        assert_eq!(at("1/8"), 250.0);
+        assert_eq!(at("1/8d"), 375.0);
+        assert!((at("1/4t") - 333.333).abs() < 0.001);
+        assert_eq!(at("1"), 2000.0);
+        assert!("fast".parse::<NoteDivision>().is_err());
+        assert!("1/0".parse::<NoteDivision>().is_err());
+    }
+    #[test]
+    fn synced_settings() {
+        let quarter: NoteDivision = "1/4".parse().unwrap();
+        let duration = json!({ "name": "duration", "min": 2.0, "max": 500.0 });
+        assert_eq!(synced_value("Delay", &duration, &quarter, 120.0).unwrap(), 500.0);
+        // Clamped to the pedal's range
+        assert_eq!(synced_value("Delay", &duration, &quarter, 60.0).unwrap(), 500.0);
+        let rate = json!({ "name": "rate", "min": 0.01, "max": 8.0 });
+        assert_eq!(synced_value("Tremelo", &rate, &quarter, 120.0).unwrap(), 2.0);
+        assert!(synced_value("Chorus", &json!({ "name": "depth" }), &quarter, 120.0).is_err());
+    }
+    #[test]
+    fn lock() {
+        let effects = json!([
+            { "name": "Delay", "settings": [{ "name": "duration", "min": 2.0, "max": 500.0, "value": 100.0 }] },
+            { "name": "Tremelo", "settings": [{ "name": "rate", "min": 0.01, "max": 8.0, "value": 1.0 }] },
+            { "name": "Reverb", "settings": [] },
+        ]);
+        let mut lock = TempoLock::default();
+        assert!(lock.settings(&effects, 120.0).unwrap().is_empty());
+        lock.enabled = true;
+        lock.divisions.insert(String::from("Delay"), String::from("1/8d"));
+        assert_eq!(
+            lock.settings(&effects, 120.0).unwrap(),
+            vec![
+                (0, json!({ "name": "duration", "value": 375.0 })),
+                (1, json!({ "name": "rate", "value": 4.0 })),
+            ]
+        );
+        assert_eq!(TimeSignature { beats: 6, unit: 8 }.bar_length(), 3.0);
+    }
+    #[test]
+    fn tap_and_clock() {
+        let start = Instant::now();
+        let at = |micros: u64| start + Duration::from_micros(micros);
+        let mut taps = TapTempo::new();
+        assert_eq!(taps.tap(at(1_000_000)), None);
+        assert_eq!(taps.tap(at(1_500_000)), Some(120.0));
+        assert!((taps.tap(at(2_100_000)).unwrap() - 109.09).abs() < 0.01);
+        // A long pause starts over
+        assert_eq!(taps.tap(at(10_000_000)), None);
+        // So does a tap from before the last one
+        assert_eq!(taps.tap(at(9_000_000)), None);
+
+        let mut clock = MidiClockIn::new();
+        let pulse = 500_000 / CLOCKS_PER_BEAT as u64;
+        let estimates: Vec<f64> = (0..CLOCKS_PER_BEAT * 3).filter_map(|n| clock.pulse(at(n as u64 * pulse))).collect();
+        assert_eq!(estimates.len(), 2);
+        assert!((estimates[0] - 120.0).abs() < 0.1);
+
+        let mut tempo = Tempo::new(SharedTempo::new(120.0));
+        assert!(!tempo.follow_clock(120.3));
+        assert!(tempo.follow_clock(121.26));
+        assert_eq!((tempo.bpm, tempo.source), (121.3, TempoSource::MidiClock));
+    }
+}
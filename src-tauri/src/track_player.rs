@@ -0,0 +1,598 @@
+//! Backing track player.
+//!
+//! A decoder thread reads the file (WAV, FLAC or MP3), turns it into stereo at our
+//! sample rate and keeps a lock-free ring buffer topped up.  The audio thread mixes the
+//! ring into the output at the track's own level.  Play, pause and level are flags the
+//! audio thread reads directly.  Seeks and loop regions go to the decoder, which has the
+//! audio thread flush what is already queued before refilling from the new spot.
+
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering}, mpsc::{self, Receiver, Sender}, Arc},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info};
+use rtrb::{Consumer, Producer, RingBuffer};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::{Decoder, DecoderOptions},
+    errors::Error as DecodeError,
+    formats::{FormatOptions, FormatReader, SeekMode, SeekTo},
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+    units::Time,
+};
+use tauri::ipc::Channel;
+
+use crate::{alsa_thread::SAMPLE_RATE, error::FxError};
+
+/// How far the decoder keeps ahead of playback (seconds)
+const RING_SECONDS: usize = 2;
+const REPORT_INTERVAL: Duration = Duration::from_millis(250);
+/// How long the decoder sleeps when the ring is full or the track has ended
+const IDLE_WAIT: Duration = Duration::from_millis(10);
+/// Give up waiting for the audio thread to flush after this long
+const FLUSH_TIMEOUT: Duration = Duration::from_millis(200);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "action", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum Transport {
+    Play,
+    Pause,
+    /// Jump to a time in seconds
+    Seek { seconds: f64 },
+    /// Play between two times (seconds) over and over
+    Loop { start: f64, end: f64 },
+    ClearLoop,
+}
+
+/// What the decoder thread is asked to do
+enum TrackControl {
+    Seek(u64),
+    Loop(Option<(u64, u64)>),
+}
+
+/// State shared by the audio thread, the decoder and the commands
+struct Shared {
+    playing: AtomicBool,
+    /// Set by the decoder, cleared by the audio thread once the ring is empty
+    flush: AtomicBool,
+    /// Reached the end of the file
+    ended: AtomicBool,
+    /// Level in dB (f32 bits)
+    level: AtomicU32,
+    /// Frames played
+    position: AtomicU64,
+    /// Loop region in frames (start == end when there is none)
+    loop_start: AtomicU64,
+    loop_end: AtomicU64,
+}
+
+impl Shared {
+    fn loop_region(&self) -> Option<(u64, u64)> {
+        let start = self.loop_start.load(Ordering::Relaxed);
+        let end = self.loop_end.load(Ordering::Relaxed);
+        (end > start).then_some((start, end))
+    }
+
+    fn set_loop(&self, region: Option<(u64, u64)>) {
+        let (start, end) = region.unwrap_or((0, 0));
+        self.loop_start.store(start, Ordering::Relaxed);
+        self.loop_end.store(end, Ordering::Relaxed);
+    }
+
+    fn as_json(&self, file: &Path, duration: Option<f64>) -> Value {
+        let seconds = |frames: u64| frames as f64 / SAMPLE_RATE as f64;
+        json!({
+            "trackEvent": {
+                "file": file,
+                "playing": self.playing.load(Ordering::Relaxed),
+                "ended": self.ended.load(Ordering::Relaxed),
+                "position": seconds(self.position.load(Ordering::Relaxed)),
+                "duration": duration,
+                "loop": self.loop_region().map(|(start, end)| json!({ "start": seconds(start), "end": seconds(end) })),
+                "level": f32::from_bits(self.level.load(Ordering::Relaxed)),
+            }
+        })
+    }
+}
+
+/// The audio thread end of the player
+pub struct TrackTap {
+    consumer: Consumer<f32>,
+    shared: Arc<Shared>,
+}
+
+impl TrackTap {
+    /// Add the next frame of the track to the output
+    pub fn mix_into(&mut self, out_a: &mut [f32], out_b: &mut [f32]) {
+        if self.shared.flush.load(Ordering::Acquire) {
+            if let Ok(queued) = self.consumer.read_chunk(self.consumer.slots()) {
+                queued.commit_all();
+            }
+            self.shared.flush.store(false, Ordering::Release);
+            return;
+        }
+        if !self.shared.playing.load(Ordering::Relaxed) {
+            return;
+        }
+        let frames = (self.consumer.slots() / 2).min(out_a.len());
+        let chunk = match self.consumer.read_chunk(frames * 2) {
+            Ok(chunk) => chunk,
+            Err(_) => return,
+        };
+        let gain = 10f32.powf(f32::from_bits(self.shared.level.load(Ordering::Relaxed)) / 20.0);
+        let (first, second) = chunk.as_slices();
+        for (i, frame) in first.chunks_exact(2).chain(second.chunks_exact(2)).enumerate() {
+            out_a[i] += gain * frame[0];
+            out_b[i] += gain * frame[1];
+        }
+        chunk.commit_all();
+        // The decoder wraps at the end of a loop so we do too
+        let mut position = self.shared.position.load(Ordering::Relaxed) + frames as u64;
+        if let Some((start, end)) = self.shared.loop_region() {
+            if position >= end {
+                position = start + (position - start) % (end - start);
+            }
+        }
+        self.shared.position.store(position, Ordering::Relaxed);
+    }
+}
+
+fn ring() -> (Producer<f32>, TrackTap, Arc<Shared>) {
+    let (producer, consumer) = RingBuffer::new(RING_SECONDS * SAMPLE_RATE as usize * 2);
+    let shared = Arc::new(Shared {
+        playing: AtomicBool::new(false),
+        flush: AtomicBool::new(false),
+        ended: AtomicBool::new(false),
+        level: AtomicU32::new(0f32.to_bits()),
+        position: AtomicU64::new(0),
+        loop_start: AtomicU64::new(0),
+        loop_end: AtomicU64::new(0),
+    });
+    (producer, TrackTap { consumer, shared: shared.clone() }, shared)
+}
+
+/// Linear interpolation from the file's sample rate to ours
+struct Resampler {
+    step: f64,
+    phase: f64,
+    last: [f32; 2],
+}
+
+impl Resampler {
+    fn new(rate: u32) -> Resampler {
+        Resampler {
+            step: rate as f64 / SAMPLE_RATE as f64,
+            phase: 0.0,
+            last: [0.0; 2],
+        }
+    }
+
+    fn reset(&mut self) {
+        self.phase = 0.0;
+        self.last = [0.0; 2];
+    }
+
+    /// Take one stereo frame in and put zero or more out
+    fn push(&mut self, frame: [f32; 2], out: &mut Vec<f32>) {
+        while self.phase < 1.0 {
+            let t = self.phase as f32;
+            out.push(self.last[0] + (frame[0] - self.last[0]) * t);
+            out.push(self.last[1] + (frame[1] - self.last[1]) * t);
+            self.phase += self.step;
+        }
+        self.phase -= 1.0;
+        self.last = frame;
+    }
+}
+
+/// An open audio file
+struct Track {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    /// Length in seconds, if the file says
+    duration: Option<f64>,
+    /// Frames to drop after a seek lands early
+    skip: u64,
+    resampler: Resampler,
+}
+
+impl Track {
+    fn open(path: &Path) -> Result<Track, FxError> {
+        let file = File::open(path)?;
+        let stream = MediaSourceStream::new(Box::new(file), Default::default());
+        let mut hint = Hint::new();
+        if let Some(ext) = path.extension().and_then(|ext| ext.to_str()) {
+            hint.with_extension(ext);
+        }
+        let probed = symphonia::default::get_probe().format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())?;
+        let format = probed.format;
+        let track = format
+            .default_track()
+            .ok_or_else(|| FxError::AudioFile(format!("{} has no audio track", path.display())))?;
+        let params = &track.codec_params;
+        let rate = params
+            .sample_rate
+            .ok_or_else(|| FxError::AudioFile(format!("{} has no sample rate", path.display())))?;
+        let decoder = symphonia::default::get_codecs().make(params, &DecoderOptions::default())?;
+        Ok(Track {
+            track_id: track.id,
+            duration: params.n_frames.map(|frames| frames as f64 / rate as f64),
+            skip: 0,
+            resampler: Resampler::new(rate),
+            format,
+            decoder,
+        })
+    }
+
+    /// Decode the next packet onto `out` as stereo at our rate.  False at the end of the file.
+    fn decode(&mut self, out: &mut Vec<f32>) -> Result<bool, FxError> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(DecodeError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+                Err(e) => return Err(e.into()),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                // A bad packet is a glitch, not the end of the song
+                Err(DecodeError::DecodeError(e)) => {
+                    debug!("skipping bad packet: {}", e);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let spec = *decoded.spec();
+            let channels = spec.channels.count().max(1);
+            let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+            samples.copy_interleaved_ref(decoded);
+            for frame in samples.samples().chunks_exact(channels) {
+                if self.skip > 0 {
+                    self.skip -= 1;
+                    continue;
+                }
+                // Mono goes to both sides, anything past stereo is dropped
+                let right = if channels > 1 { frame[1] } else { frame[0] };
+                self.resampler.push([frame[0], right], out);
+            }
+            return Ok(true);
+        }
+    }
+
+    fn seek(&mut self, seconds: f64) -> Result<(), FxError> {
+        let seeked = self.format.seek(
+            SeekMode::Accurate,
+            SeekTo::Time { time: Time::from(seconds), track_id: Some(self.track_id) },
+        )?;
+        self.skip = seeked.required_ts.saturating_sub(seeked.actual_ts);
+        self.decoder.reset();
+        self.resampler.reset();
+        Ok(())
+    }
+}
+
+/// The decoder thread's side: keeps the ring full from the track
+struct Feeder {
+    track: Track,
+    producer: Producer<f32>,
+    shared: Arc<Shared>,
+    pending: Vec<f32>,
+    offset: usize,
+    /// The frame the next sample pushed will play at
+    next_frame: u64,
+}
+
+impl Feeder {
+    /// Throw away what is queued and refill from `frame`
+    fn seek(&mut self, frame: u64) -> Result<(), FxError> {
+        self.shared.flush.store(true, Ordering::Release);
+        let asked = Instant::now();
+        while self.shared.flush.load(Ordering::Acquire) && asked.elapsed() < FLUSH_TIMEOUT {
+            thread::sleep(Duration::from_millis(1));
+        }
+        self.shared.position.store(frame, Ordering::Relaxed);
+        self.shared.ended.store(false, Ordering::Relaxed);
+        self.jump(frame)
+    }
+
+    /// Carry on decoding from `frame` while what is queued plays out
+    fn jump(&mut self, frame: u64) -> Result<(), FxError> {
+        self.track.seek(frame as f64 / SAMPLE_RATE as f64)?;
+        self.pending.clear();
+        self.offset = 0;
+        self.next_frame = frame;
+        Ok(())
+    }
+
+    /// Push what fits into the ring.  False when there was nothing to do.
+    fn fill(&mut self) -> Result<bool, FxError> {
+        if self.offset == self.pending.len() {
+            self.pending.clear();
+            self.offset = 0;
+            let region = self.shared.loop_region();
+            if let Some((start, end)) = region {
+                if self.next_frame >= end {
+                    self.jump(start)?;
+                }
+            }
+            if !self.track.decode(&mut self.pending)? {
+                if let Some((start, _)) = region {
+                    self.jump(start)?;
+                    return Ok(true);
+                }
+                self.shared.ended.store(true, Ordering::Relaxed);
+                return Ok(false);
+            }
+            // Stop right at the end of the loop
+            if let Some((_, end)) = region {
+                self.pending.truncate(end.saturating_sub(self.next_frame) as usize * 2);
+            }
+        }
+        // Whole stereo frames only
+        let count = self.producer.slots().min(self.pending.len() - self.offset) & !1;
+        if count == 0 {
+            return Ok(false);
+        }
+        if let Ok(chunk) = self.producer.write_chunk_uninit(count) {
+            chunk.fill_from_iter(self.pending[self.offset..self.offset + count].iter().copied());
+        }
+        self.offset += count;
+        self.next_frame += (count / 2) as u64;
+        Ok(true)
+    }
+
+    fn control(&mut self, control: TrackControl) -> Result<(), FxError> {
+        match control {
+            TrackControl::Seek(frame) => {
+                // With a loop set playback stays inside it
+                let frame = match self.shared.loop_region() {
+                    Some((start, end)) if frame < start || frame >= end => start,
+                    _ => frame,
+                };
+                self.seek(frame)
+            }
+            TrackControl::Loop(region) => {
+                let position = self.shared.position.load(Ordering::Relaxed);
+                self.shared.set_loop(region);
+                // Start the loop, or get the queue back in line with the position
+                self.seek(region.map_or(position, |(start, _)| start))
+            }
+        }
+    }
+}
+
+/// Runs the decoder thread for the loaded file.  The matching
+/// [`TrackTap`](TrackTap) lives on the audio thread.
+pub struct TrackPlayer {
+    producer: Option<Producer<f32>>,
+    shared: Arc<Shared>,
+    event_channel: Channel<Value>,
+    /// The loaded file and its length
+    file: Option<(PathBuf, Option<f64>)>,
+    control: Option<Sender<TrackControl>>,
+    running: Option<Arc<AtomicBool>>,
+    handle: Option<JoinHandle<Producer<f32>>>,
+}
+
+impl TrackPlayer {
+    pub fn new(event_channel: Channel<Value>) -> (TrackPlayer, TrackTap) {
+        let (producer, tap, shared) = ring();
+        let player = TrackPlayer {
+            producer: Some(producer),
+            shared,
+            event_channel,
+            file: None,
+            control: None,
+            running: None,
+            handle: None,
+        };
+        (player, tap)
+    }
+
+    /// Open a file, paused at the start
+    pub fn load(&mut self, path: &Path) -> Result<Value, FxError> {
+        // Make sure the new file is good before dropping the old one
+        let track = Track::open(path)?;
+        if self.running.is_some() {
+            self.unload()?;
+        }
+        info!("loading track {}", path.display());
+        let duration = track.duration;
+        self.shared.set_loop(None);
+        let (control_tx, control_rx) = mpsc::channel();
+        let running = Arc::new(AtomicBool::new(true));
+        let mut feeder = Feeder {
+            track,
+            producer: self.producer.take().ok_or(FxError::TrackPlayerFailed)?,
+            shared: self.shared.clone(),
+            pending: Vec::new(),
+            offset: 0,
+            next_frame: 0,
+        };
+        let thread_running = running.clone();
+        let event_channel = self.event_channel.clone();
+        let file = path.to_path_buf();
+        let handle = thread::Builder::new().name("Track Player".to_string()).spawn(move || {
+            if let Err(e) = feeder.seek(0) {
+                error!("track seek failed: {}", e);
+            }
+            run_feeder(&mut feeder, &control_rx, &thread_running, &event_channel, &file, duration);
+            feeder.producer
+        })?;
+        self.control = Some(control_tx);
+        self.running = Some(running);
+        self.handle = Some(handle);
+        self.file = Some((path.to_path_buf(), duration));
+        Ok(self.shared.as_json(path, duration))
+    }
+
+    pub fn unload(&mut self) -> Result<(), FxError> {
+        match self.running.take() {
+            Some(running) => running.store(false, Ordering::Relaxed),
+            None => return Err(FxError::NotFound(String::from("no track loaded"))),
+        }
+        self.shared.playing.store(false, Ordering::Relaxed);
+        self.control = None;
+        self.file = None;
+        if let Some(handle) = self.handle.take() {
+            let producer = handle
+                .join()
+                .map_err(|_| FxError::Io(String::from("track player thread panicked")))?;
+            self.producer = Some(producer);
+        }
+        Ok(())
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.running.is_some()
+    }
+
+    pub fn transport(&mut self, transport: Transport) -> Result<Value, FxError> {
+        let (file, duration) = self
+            .file
+            .clone()
+            .ok_or_else(|| FxError::NotFound(String::from("no track loaded")))?;
+        let frame = |seconds: f64| (seconds.max(0.0) * SAMPLE_RATE as f64) as u64;
+        match transport {
+            Transport::Play => {
+                // Play after the end starts over
+                if self.shared.ended.load(Ordering::Relaxed) && self.shared.loop_region().is_none() {
+                    self.send(TrackControl::Seek(0))?;
+                }
+                self.shared.playing.store(true, Ordering::Relaxed);
+            }
+            Transport::Pause => self.shared.playing.store(false, Ordering::Relaxed),
+            Transport::Seek { seconds } => self.send(TrackControl::Seek(frame(seconds)))?,
+            Transport::Loop { start, end } => {
+                if start < 0.0 || start >= end || duration.is_some_and(|duration| start >= duration) {
+                    return Err(FxError::ProtocolParse(format!("bad loop region {} - {}", start, end)));
+                }
+                self.send(TrackControl::Loop(Some((frame(start), frame(end)))))?;
+            }
+            Transport::ClearLoop => self.send(TrackControl::Loop(None))?,
+        }
+        Ok(self.shared.as_json(&file, duration))
+    }
+
+    /// Track level in dB
+    pub fn set_level(&mut self, level: f32) -> Value {
+        self.shared.level.store(level.to_bits(), Ordering::Relaxed);
+        match &self.file {
+            Some((file, duration)) => self.shared.as_json(file, *duration),
+            None => self.shared.as_json(Path::new(""), None),
+        }
+    }
+
+    fn send(&self, control: TrackControl) -> Result<(), FxError> {
+        match &self.control {
+            Some(control_tx) => Ok(control_tx.send(control)?),
+            None => Err(FxError::NotFound(String::from("no track loaded"))),
+        }
+    }
+}
+
+// The decoder thread: fill the ring, follow the transport and report the position
+fn run_feeder(
+    feeder: &mut Feeder,
+    control_rx: &Receiver<TrackControl>,
+    running: &AtomicBool,
+    event_channel: &Channel<Value>,
+    file: &Path,
+    duration: Option<f64>,
+) {
+    let mut last_report = Instant::now();
+    while running.load(Ordering::Relaxed) {
+        let busy = match feeder.fill() {
+            Ok(busy) => busy,
+            Err(e) => {
+                error!("track decode failed: {}", e);
+                let mut event = feeder.shared.as_json(file, duration);
+                event["trackEvent"]["error"] = json!(e);
+                if let Err(e) = event_channel.send(event) {
+                    debug!("track event not sent: {}", e);
+                }
+                feeder.shared.ended.store(true, Ordering::Relaxed);
+                break;
+            }
+        };
+        let control = if busy { control_rx.try_recv().ok() } else { control_rx.recv_timeout(IDLE_WAIT).ok() };
+        let mut report = last_report.elapsed() >= REPORT_INTERVAL && feeder.shared.playing.load(Ordering::Relaxed);
+        if let Some(control) = control {
+            if let Err(e) = feeder.control(control) {
+                error!("track seek failed: {}", e);
+            }
+            report = true;
+        }
+        if report {
+            last_report = Instant::now();
+            if let Err(e) = event_channel.send(feeder.shared.as_json(file, duration)) {
+                debug!("track event not sent: {}", e);
+            }
+        }
+    }
+    info!("track player ended: {}", file.display());
+}
+
+#[cfg(test)]
+mod test_track_player {
+    use super::*;
+    use crate::utils::test_utils::TempDir;
+
+    #[test]
+    fn resamples_to_stereo() {
+        let dir = TempDir::new("track");
+        let path = dir.path().join("track.wav");
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE / 2,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for _ in 0..2400 {
+            writer.write_sample(i16::MAX / 2).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let mut track = Track::open(&path).unwrap();
+        assert_eq!(track.duration, Some(0.1));
+        let mut out = Vec::new();
+        while track.decode(&mut out).unwrap() {}
+        // Twice the frames, both sides
+        assert_eq!(out.len(), 2 * 2400 * 2);
+        assert!((out[100] - 0.5).abs() < 0.01);
+        assert_eq!(out[100], out[101]);
+
+        out.clear();
+        track.seek(0.05).unwrap();
+        while track.decode(&mut out).unwrap() {}
+        assert_eq!(out.len(), 2 * 1200 * 2);
+    }
+
+    #[test]
+    fn loop_wraps_position() {
+        let (mut producer, mut tap, shared) = ring();
+        shared.set_loop(Some((100, 200)));
+        shared.position.store(150, Ordering::Relaxed);
+        shared.playing.store(true, Ordering::Relaxed);
+        for _ in 0..128 {
+            producer.push(1.0).unwrap();
+            producer.push(-1.0).unwrap();
+        }
+        let (mut out_a, mut out_b) = ([0.0; 128], [0.0; 128]);
+        tap.mix_into(&mut out_a, &mut out_b);
+        assert_eq!((out_a[0], out_b[127]), (1.0, -1.0));
+        assert_eq!(shared.position.load(Ordering::Relaxed), 178);
+    }
+}
@@ -1,4 +1,9 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fs, path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::FxError;
+
 // Get the time in microseconds
 pub fn get_micro_time() -> u128 {
     SystemTime::now()
@@ -41,4 +46,72 @@ impl MicroTimer {
     pub fn _since(&mut self, now: u128) -> u128 {
         now - self.last_time
     }
-}
\ No newline at end of file
+}
+/// Numeric ids of the `<id>.json` files in a directory, sorted
+pub fn json_file_ids(dir: &Path) -> Result<Vec<u64>, FxError> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            if let Some(id) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+                ids.push(id);
+            }
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+/// Read a JSON file into a structure
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, FxError> {
+    let data = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&data)?)
+}
+
+/// Write a structure as pretty JSON.  The data goes to a temp file first so a crash
+/// can't leave a half written file behind.
+pub fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), FxError> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_string_pretty(value)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod test_utils {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    use super::get_micro_time;
+
+    static NEXT_DIR: AtomicU64 = AtomicU64::new(0);
+
+    /// Scratch directory for a test, removed when it is dropped
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!(
+                "fx-board-{}-{}-{}",
+                name,
+                get_micro_time(),
+                NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+}
@@ -16,6 +16,7 @@ export enum RTJamParameters {
     paramMovePedal,
     paramLoadBoard,
     paramTuneChannel,
+    paramLooperControl = 36,
     paramShutdownAudio = 9999,
   }
   
@@ -103,6 +104,9 @@ export class UnitHandler {
         ]);
       }
     }
+    if (msg.midiEvent) {
+      this.setMidiEvent(msg.midiEvent);
+    }
   }
 
   apiFunction(msg: any) {
@@ -221,4 +225,13 @@ export class UnitHandler {
       sValue: JSON.stringify(config),
     });
   }
+
+  // action: 0 record, 1 overdub, 2 play, 3 stop, 4 undo, 5 clear.  channel -1 is the master bus
+  looperControl(action: number, channel: number) {
+    this.apiFunction({
+      param: RTJamParameters.paramLooperControl,
+      iValue1: action,
+      iValue2: channel,
+    });
+  }
 }